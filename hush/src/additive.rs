@@ -0,0 +1,434 @@
+//! Additive synthesis.
+//!
+//! Additive synthesis builds sounds by summing sine *partials*, each with its own frequency ratio
+//! and amplitude. Harmonic partials (integer ratios) give organs and other tonal sounds; inharmonic
+//! ones give bells and gongs. Partials can have their own envelope, so that high partials die out
+//! before low ones, as they do in most real instruments.
+//!
+//! Partials at or above Nyquist for the note being played are dropped, so that additive sounds
+//! never alias.
+//!
+//! `AdditiveWave` is a plain wave – with no envelopes – that can be played by an `Oscillator` or a
+//! `Synth`. `Additive` is a polyphonic instrument supporting per-partial envelopes.
+
+use alloc::vec::Vec;
+use core::intrinsics::powf32;
+
+use envelope::{ADSR, Envelope};
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use note::Note;
+use oscillator::{Wave, sine_wave, wrap_phase};
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices};
+
+/// A sine partial.
+#[derive(Clone, Debug)]
+pub struct Partial {
+  /// Frequency of the partial relative to the note’s frequency.
+  pub ratio: f32,
+  pub amplitude: f32,
+  /// Envelope of the partial; partials without one follow the envelope of the instrument.
+  pub envelope: Option<ADSR>
+}
+
+impl Partial {
+  pub fn new(ratio: f32, amplitude: f32) -> Self {
+    Partial {
+      ratio,
+      amplitude,
+      envelope: None
+    }
+  }
+
+  /// Give the partial its own envelope.
+  pub fn with_envelope(self, envelope: ADSR) -> Self {
+    Partial { envelope: Some(envelope), ..self }
+  }
+}
+
+/// Harmonic partials: the amplitude of the fundamental, then of the second harmonic, etc.
+pub fn harmonics(amplitudes: &[f32]) -> Vec<Partial> {
+  amplitudes.iter().enumerate().map(|(i, &amplitude)| Partial::new((i + 1) as f32, amplitude)).collect()
+}
+
+/// A sum of sine partials, as a wave.
+///
+/// Envelopes of the partials are ignored.
+#[derive(Clone, Debug)]
+pub struct AdditiveWave {
+  // ratio and amplitude of each partial
+  partials: Vec<(f32, f32)>,
+  phases: Vec<f32>
+}
+
+impl AdditiveWave {
+  pub fn new(partials: &[Partial]) -> Self {
+    let mut phases = Vec::new();
+    phases.resize(partials.len(), 0.);
+
+    AdditiveWave {
+      partials: partials.iter().map(|partial| (partial.ratio, partial.amplitude)).collect(),
+      phases
+    }
+  }
+
+  /// Harmonic partials; see `harmonics`.
+  pub fn harmonics(amplitudes: &[f32]) -> Self {
+    Self::new(&harmonics(amplitudes))
+  }
+}
+
+impl Wave for AdditiveWave {
+  fn sample(&mut self, _: Hertz, dt: Hertz) -> Sample {
+    let mut signal = 0.;
+
+    for (&(ratio, amplitude), phase) in self.partials.iter().zip(&mut self.phases) {
+      let partial_dt = dt * ratio;
+
+      // drop partials above Nyquist
+      if partial_dt < 0.5 {
+        signal += sine_wave(*phase) * amplitude;
+      }
+
+      *phase = wrap_phase(*phase + partial_dt);
+    }
+
+    signal
+  }
+
+  fn reset(&mut self) {
+    for phase in &mut self.phases {
+      *phase = 0.;
+    }
+  }
+}
+
+/// A polyphonic additive synth.
+///
+/// Each note is played on its own voice, summing all partials. Partials with an envelope are shaped
+/// by it; the others are shaped by the synth’s envelope.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Notes are scaled by their velocity and panned by their expression. Their timbre tilts the
+/// spectrum: the amplitude of each partial is multiplied by its ratio raised to the timbre, so that
+/// positive timbres favor high partials and negative ones low partials.
+pub struct Additive {
+  partials: Vec<Partial>,
+  envelope: ADSR,
+  rate: SampleRate,
+  voices: Voices<AdditiveVoice>
+}
+
+impl Additive {
+  pub fn new(partials: Vec<Partial>, envelope: ADSR, rate: SampleRate) -> Self {
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |_| AdditiveVoice::new(&partials, &envelope, rate));
+
+    Additive {
+      partials,
+      envelope,
+      rate,
+      voices
+    }
+  }
+
+  /// A drawbar organ: sub-octave, fundamental, fifth and upper harmonics.
+  pub fn organ(rate: SampleRate) -> Self {
+    let partials = [
+      Partial::new(0.5, 0.3),
+      Partial::new(1., 0.4),
+      Partial::new(1.5, 0.2),
+      Partial::new(2., 0.3),
+      Partial::new(3., 0.15),
+      Partial::new(4., 0.2),
+      Partial::new(6., 0.1),
+      Partial::new(8., 0.1)
+    ].to_vec();
+
+    Self::new(partials, ADSR::valid(0.005, 0.01, 1., 0.05, rate), rate)
+  }
+
+  /// A Risset-style bell: inharmonic partials, the highest dying out first.
+  pub fn bell(rate: SampleRate) -> Self {
+    let partials = [
+      (0.56, 0.25, 6.),
+      (0.92, 0.2, 4.),
+      (1.19, 0.25, 3.),
+      (1.71, 0.2, 2.5),
+      (2., 0.15, 2.),
+      (2.74, 0.15, 1.5),
+      (3., 0.1, 1.2),
+      (3.76, 0.1, 1.),
+      (4.07, 0.1, 0.8)
+    ].iter().map(|&(ratio, amplitude, decay)| {
+      Partial::new(ratio, amplitude).with_envelope(ADSR::valid(0.002, decay, 0., decay, rate))
+    }).collect();
+
+    Self::new(partials, ADSR::valid(0.002, 0.01, 1., 0.5, rate), rate)
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Partials.
+  pub fn partials(&self) -> &[Partial] {
+    &self.partials
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<AdditiveVoice> {
+    Voices::new(count, self.voices.stealing(), |_| AdditiveVoice::new(&self.partials, &self.envelope, self.rate))
+  }
+}
+
+impl Modulable for Additive {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.voices.modulate(param, amount);
+  }
+}
+
+impl Instrument for Additive {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+// A partial of a voice.
+struct VoicePartial {
+  ratio: f32,
+  amplitude: f32,
+  envelope: Option<ADSR>,
+  phase: f32
+}
+
+/// A voice of an `Additive` synth.
+pub struct AdditiveVoice {
+  partials: Vec<VoicePartial>,
+  envelope: ADSR,
+  step: f32,
+  freq: Hertz,
+  velocity: f32,
+  // spectral tilt, from the timbre
+  tilt: f32,
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  gate: bool,
+  level: f32,
+  // partials following the voice’s envelope
+  buffer: Vec<Sample>,
+  levels: Vec<f32>,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl AdditiveVoice {
+  fn new(partials: &[Partial], envelope: &ADSR, rate: SampleRate) -> Self {
+    let partials = partials.iter().map(|partial| VoicePartial {
+      ratio: partial.ratio,
+      amplitude: partial.amplitude,
+      envelope: partial.envelope.clone(),
+      phase: 0.
+    }).collect();
+
+    AdditiveVoice {
+      partials,
+      envelope: envelope.clone(),
+      step: rate.step(),
+      freq: 0.,
+      velocity: 1.,
+      tilt: 0.,
+      pitch_factor: 1.,
+      gain: 1.,
+      gate: false,
+      level: 0.,
+      buffer: Vec::new(),
+      levels: Vec::new(),
+      pan: (1., 1.)
+    }
+  }
+
+  // Highest level of all envelopes in use.
+  fn current_level(&self, t: SampleTime) -> f32 {
+    self.partials.iter()
+      .filter_map(|partial| partial.envelope.as_ref())
+      .map(|envelope| envelope.get(t))
+      .fold(self.envelope.get(t), f32::max)
+  }
+}
+
+impl Voice for AdditiveVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.freq = note.frequency();
+    self.velocity = expression.velocity;
+    self.tilt = expression.timbre.unwrap_or(0.);
+    self.pan = expression.pan_gains();
+    self.gate = true;
+    self.envelope.on(t);
+
+    for partial in &mut self.partials {
+      partial.phase = 0.;
+
+      if let Some(ref mut envelope) = partial.envelope {
+        envelope.on(t);
+      }
+    }
+
+    self.level = self.current_level(t);
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+    self.envelope.off(t);
+
+    for partial in &mut self.partials {
+      if let Some(ref mut envelope) = partial.envelope {
+        envelope.off(t);
+      }
+    }
+  }
+
+  fn is_active(&self) -> bool {
+    self.gate || self.level > 0.
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let len = out.len();
+    let end = SampleTime(start.0 + len);
+    let dt = self.freq * self.pitch_factor * self.step;
+    let gain = self.gain * self.velocity;
+
+    self.buffer.clear();
+    self.buffer.resize(len, 0.);
+
+    for partial in &mut self.partials {
+      let partial_dt = dt * partial.ratio;
+
+      // drop partials above Nyquist
+      if partial_dt >= 0.5 {
+        continue;
+      }
+
+      let amplitude = partial.amplitude * unsafe { powf32(partial.ratio, self.tilt) };
+
+      match partial.envelope {
+        // partials with their own envelope go straight to the output
+        Some(ref mut envelope) => {
+          self.levels.resize(len, 0.);
+          envelope.render(start, &mut self.levels);
+
+          for (sample, level) in out.iter_mut().zip(&self.levels) {
+            *sample += sine_wave(partial.phase) * amplitude * level * gain;
+            partial.phase = wrap_phase(partial.phase + partial_dt);
+          }
+        }
+
+        None => {
+          for sample in &mut self.buffer {
+            *sample += sine_wave(partial.phase) * amplitude;
+            partial.phase = wrap_phase(partial.phase + partial_dt);
+          }
+        }
+      }
+    }
+
+    self.envelope.apply(start, &mut self.buffer);
+
+    for (sample, signal) in out.iter_mut().zip(&self.buffer) {
+      *sample += signal * gain;
+    }
+
+    self.level = self.current_level(end);
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::A4;
+  use oscillator::Oscillator;
+
+  const RATE: SampleRate = SampleRate(48000);
+
+  #[test]
+  fn partials_above_nyquist_add_nothing() {
+    // 10 kHz: the third partial is at 30 kHz, above Nyquist
+    let mut both = Oscillator::new(AdditiveWave::new(&[Partial::new(1., 0.5), Partial::new(3., 0.5)]), RATE);
+    let mut first = Oscillator::new(AdditiveWave::new(&[Partial::new(1., 0.5)]), RATE);
+
+    for i in 0..1000 {
+      assert_eq!(both.next_sample(10000.), first.next_sample(10000.), "sample {}", i);
+    }
+
+    // A4 is at 440 Hz; ratio 60 puts the partial at 26.4 kHz
+    let envelope = ADSR::valid(0.001, 0.01, 1., 0.01, RATE);
+    let mut both = Additive::new([Partial::new(1., 0.5), Partial::new(60., 0.5)].to_vec(), envelope.clone(), RATE);
+    let mut first = Additive::new([Partial::new(1., 0.5)].to_vec(), envelope, RATE);
+
+    both.note_on(A4, NoteChannel::new(0));
+    first.note_on(A4, NoteChannel::new(0));
+
+    assert_eq!(both.get_samples(SampleTime(0), SampleTime(1000)), first.get_samples(SampleTime(0), SampleTime(1000)));
+  }
+
+  #[test]
+  fn reset_restarts_partials() {
+    let mut oscillator = Oscillator::new(AdditiveWave::harmonics(&[1., 0.5, 0.25]), RATE);
+    let start: Vec<Sample> = (0..100).map(|_| oscillator.next_sample(440.)).collect();
+
+    for _ in 0..1234 {
+      oscillator.next_sample(440.);
+    }
+
+    oscillator.reset();
+
+    for (i, &sample) in start.iter().enumerate() {
+      assert_eq!(oscillator.next_sample(440.), sample, "sample {}", i);
+    }
+  }
+}
@@ -0,0 +1,221 @@
+//! Oscillator combinators.
+//!
+//! Combinators are waves built out of other waves, to be played by an `Oscillator` – or a `Synth`,
+//! with `Synth::from_wave`:
+//!
+//!   - `HardSync` hard-syncs a wave: its phase is reset each time the oscillator starts a new
+//!     period, giving the aggressive, tearing sound of sync leads.
+//!   - `Ring` multiplies two waves (ring modulation), giving metallic, bell-like tones.
+//!   - `Mix` crossfades between two waves.
+//!
+//! The second wave of a combinator runs at a frequency *ratio* of the oscillator’s frequency, with
+//! its own phase, which restarts when the oscillator is reset. All fields are public, so that
+//! ratios and mix amounts can be changed between samples through `Oscillator::wave_mut`.
+
+use hertz::Hertz;
+use oscillator::{Wave, wrap_phase};
+use sample::Sample;
+
+/// A hard-synced wave.
+///
+/// The oscillator playing a `HardSync` is the *master*: it isn’t heard, but each time it starts a
+/// new period, the phase of the *slave* wave is reset. The slave runs at `ratio` times the master’s
+/// frequency; ratios above 1 give the typical sync sound, and sweeping the ratio gives the classic
+/// sync sweep.
+#[derive(Clone, Debug)]
+pub struct HardSync<W> {
+  pub slave: W,
+  pub ratio: f32,
+  phase: f32
+}
+
+impl<W> HardSync<W> where W: Wave {
+  pub fn new(slave: W, ratio: f32) -> Self {
+    HardSync {
+      slave,
+      ratio,
+      phase: 0.
+    }
+  }
+}
+
+impl<W> Wave for HardSync<W> where W: Wave {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    let slave_dt = dt * self.ratio;
+
+    // the master just started a new period; keep the part of the sample elapsed since then
+    if t < dt {
+      self.phase = wrap_phase(t * self.ratio);
+    }
+
+    let signal = self.slave.sample(self.phase, slave_dt);
+    self.phase = wrap_phase(self.phase + slave_dt);
+
+    signal
+  }
+
+  fn reset(&mut self) {
+    self.slave.reset();
+    self.phase = 0.;
+  }
+}
+
+/// Ring modulation of two waves.
+///
+/// The carrier is played at the oscillator’s frequency and multiplied by the modulator, played at
+/// `ratio` times that frequency. Non-integer ratios give inharmonic, metallic tones.
+#[derive(Clone, Debug)]
+pub struct Ring<C, M> {
+  pub carrier: C,
+  pub modulator: M,
+  pub ratio: f32,
+  phase: f32
+}
+
+impl<C, M> Ring<C, M> where C: Wave, M: Wave {
+  pub fn new(carrier: C, modulator: M, ratio: f32) -> Self {
+    Ring {
+      carrier,
+      modulator,
+      ratio,
+      phase: 0.
+    }
+  }
+}
+
+impl<C, M> Wave for Ring<C, M> where C: Wave, M: Wave {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    let modulator_dt = dt * self.ratio;
+    let signal = self.carrier.sample(t, dt) * self.modulator.sample(self.phase, modulator_dt);
+
+    self.phase = wrap_phase(self.phase + modulator_dt);
+
+    signal
+  }
+
+  fn reset(&mut self) {
+    self.carrier.reset();
+    self.modulator.reset();
+    self.phase = 0.;
+  }
+}
+
+/// Mix of two waves.
+///
+/// The first wave is played at the oscillator’s frequency, the second one at `ratio` times that
+/// frequency. `mix` goes from 0 (first wave only) to 1 (second wave only).
+#[derive(Clone, Debug)]
+pub struct Mix<A, B> {
+  pub first: A,
+  pub second: B,
+  pub ratio: f32,
+  pub mix: f32,
+  phase: f32
+}
+
+impl<A, B> Mix<A, B> where A: Wave, B: Wave {
+  pub fn new(first: A, second: B, ratio: f32, mix: f32) -> Self {
+    Mix {
+      first,
+      second,
+      ratio,
+      mix,
+      phase: 0.
+    }
+  }
+}
+
+impl<A, B> Wave for Mix<A, B> where A: Wave, B: Wave {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    let second_dt = dt * self.ratio;
+    let a = self.first.sample(t, dt);
+    let b = self.second.sample(self.phase, second_dt);
+
+    self.phase = wrap_phase(self.phase + second_dt);
+
+    a + (b - a) * self.mix
+  }
+
+  fn reset(&mut self) {
+    self.first.reset();
+    self.second.reset();
+    self.phase = 0.;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use oscillator::{Oscillator, sine_wave};
+  use time::SampleRate;
+
+  const RATE: SampleRate = SampleRate(48000);
+  // 128 samples per period, so that phases are exact
+  const FREQ: Hertz = 375.;
+  const DT: Hertz = 1. / 128.;
+
+  // A wave giving its own phase.
+  fn ramp(t: Hertz) -> Sample {
+    t
+  }
+
+  #[test]
+  fn hard_sync_resets_slave_at_each_master_period() {
+    let mut oscillator = Oscillator::new(HardSync::new(ramp, 2.5), RATE);
+
+    for i in 0 .. 4 * 128 {
+      let expected = wrap_phase((i % 128) as f32 * DT * 2.5);
+      assert_eq!(oscillator.next_sample(FREQ), expected, "sample {}", i);
+    }
+  }
+
+  #[test]
+  fn ring_multiplies_waves() {
+    let mut oscillator = Oscillator::new(Ring::new(sine_wave, sine_wave, 2.5), RATE);
+
+    for i in 0 .. 4 * 128 {
+      let t = i as f32 * DT;
+      let expected = sine_wave(wrap_phase(t)) * sine_wave(wrap_phase(t * 2.5));
+      let signal = oscillator.next_sample(FREQ);
+
+      assert!((signal - expected).abs() < 1e-5, "sample {}: {} instead of {}", i, signal, expected);
+    }
+  }
+
+  #[test]
+  fn mix_crossfades_waves() {
+    let mut oscillator = Oscillator::new(Mix::new(ramp, ramp, 2.5, 0.25), RATE);
+
+    for i in 0 .. 4 * 128 {
+      let t = i as f32 * DT;
+      let expected = 0.75 * wrap_phase(t) + 0.25 * wrap_phase(t * 2.5);
+      let signal = oscillator.next_sample(FREQ);
+
+      assert!((signal - expected).abs() < 1e-5, "sample {}: {} instead of {}", i, signal, expected);
+    }
+  }
+
+  #[test]
+  fn reset_restarts_second_waves() {
+    let mut ring = Oscillator::new(Ring::new(ramp, ramp, 2.5), RATE);
+    let mut mix = Oscillator::new(Mix::new(ramp, ramp, 2.5, 1.), RATE);
+
+    for _ in 0 .. 100 {
+      ring.next_sample(FREQ);
+      mix.next_sample(FREQ);
+    }
+
+    ring.reset();
+    mix.reset();
+
+    for i in 0 .. 128 {
+      let t = i as f32 * DT;
+
+      assert_eq!(ring.next_sample(FREQ), t * wrap_phase(t * 2.5));
+      assert_eq!(mix.next_sample(FREQ), wrap_phase(t * 2.5));
+    }
+  }
+}
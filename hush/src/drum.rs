@@ -0,0 +1,537 @@
+//! Synthesized drums.
+//!
+//! `DrumMachine` is a drum kit that doesn’t need any sample: each drum is a pitch-swept sine tone
+//! mixed with filtered noise, each with its own one-shot envelope:
+//!
+//!   - The kick is a sine swept down from a punchy attack to a deep tail, with a click of noise.
+//!   - The snare mixes a short tone with high-passed noise.
+//!   - The clap is band-passed noise with a few quick bursts before its tail.
+//!   - Hi-hats are high-passed noise; closed hats choke open ones.
+//!   - Toms are swept sines tuned by the note that triggers them.
+//!
+//! Drums are triggered by notes in the General MIDI percussion layout (see `Drum::from_note`).
+
+use alloc::vec::Vec;
+use core::intrinsics::{exp2f32, expf32, floorf32};
+
+use envelope::{Breakpoint, Breakpoints, Curve, Envelope};
+use filter::Biquad;
+use hertz::Hertz;
+use instrument::{Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use noise::{Noise, NoiseColor};
+use note::Note;
+use oscillator::{sine_wave, wrap_phase};
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices, voice_seed};
+
+/// Default number of voices of each drum.
+pub const DEFAULT_DRUM_VOICES: usize = 4;
+
+/// Default seed of the drum noise.
+pub const DEFAULT_SEED: u32 = 0x1d7a_52e9;
+
+// Time constant of the fade of a choked drum.
+const CHOKE_TIME: Time = 0.005;
+
+// Level under which a choked drum is considered silent.
+const SILENCE: f32 = 1e-4;
+
+/// A drum of a `DrumMachine`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Drum {
+  Kick,
+  Snare,
+  Clap,
+  ClosedHat,
+  OpenHat,
+  /// Toms are tuned by their note.
+  Tom
+}
+
+impl Drum {
+  /// All drums, in the order of `Drum::index`.
+  pub const ALL: [Drum; 6] = [Drum::Kick, Drum::Snare, Drum::Clap, Drum::ClosedHat, Drum::OpenHat, Drum::Tom];
+
+  /// Drum played by a note, in the General MIDI percussion layout:
+  ///
+  ///   - 35 (B1) and 36 (C2): kick.
+  ///   - 38 (D2) and 40 (E2): snare.
+  ///   - 39 (EB2): clap.
+  ///   - 42 (GB2) and 44 (AB2): closed hi-hat.
+  ///   - 46 (BB2): open hi-hat.
+  ///   - 41 (F2), 43 (G2), 45 (A2), 47 (B2), 48 (C3) and 50 (D3): toms, from the lowest to the
+  ///     highest.
+  ///
+  /// Other notes don’t play any drum.
+  pub fn from_note(note: Note) -> Option<Self> {
+    match note_number(note) {
+      35 | 36 => Some(Drum::Kick),
+      38 | 40 => Some(Drum::Snare),
+      39 => Some(Drum::Clap),
+      42 | 44 => Some(Drum::ClosedHat),
+      46 => Some(Drum::OpenHat),
+      41 | 43 | 45 | 47 | 48 | 50 => Some(Drum::Tom),
+      _ => None
+    }
+  }
+
+  /// Index of the drum in `Drum::ALL`.
+  pub fn index(&self) -> usize {
+    match *self {
+      Drum::Kick => 0,
+      Drum::Snare => 1,
+      Drum::Clap => 2,
+      Drum::ClosedHat => 3,
+      Drum::OpenHat => 4,
+      Drum::Tom => 5
+    }
+  }
+}
+
+// Nearest MIDI note number.
+fn note_number(note: Note) -> i32 {
+  unsafe { floorf32(note.number() + 0.5) as i32 }
+}
+
+// Pitch of a tom: from 90 Hz for the low floor tom (41) up an octave for the high tom (50).
+fn tom_frequency(note: Note) -> Hertz {
+  90. * unsafe { exp2f32((note.number() - 41.) / 9.) }
+}
+
+/// A synthesized drum kit.
+///
+/// Each drum has its own voices, so that drums never steal each other’s voices and each drum can be
+/// played polyphonically on several note channels – e.g. to let a crash of toms ring.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Drums are scaled by the velocity of their note and panned by its expression.
+pub struct DrumMachine {
+  rate: SampleRate,
+  seed: u32,
+  // voices of each drum, in the order of `Drum::ALL`
+  drums: Vec<Voices<DrumVoice>>,
+  mixing_buffer: Vec<Sample>
+}
+
+impl DrumMachine {
+  pub fn new(rate: SampleRate) -> Self {
+    DrumMachine {
+      rate,
+      seed: DEFAULT_SEED,
+      drums: Self::make_drums(DEFAULT_DRUM_VOICES, DEFAULT_SEED, rate),
+      mixing_buffer: Vec::new()
+    }
+  }
+
+  /// Change the number of voices of each drum.
+  ///
+  /// All currently playing drums are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.drums = Self::make_drums(count, self.seed, self.rate);
+    self
+  }
+
+  /// Change the seed of the drum noise.
+  ///
+  /// All currently playing drums are cut.
+  pub fn with_seed(mut self, seed: u32) -> Self {
+    let count = self.drums[0].len();
+
+    self.seed = seed;
+    self.drums = Self::make_drums(count, seed, self.rate);
+    self
+  }
+
+  // All voices of all drums are given different seeds.
+  fn make_drums(count: usize, seed: u32, rate: SampleRate) -> Vec<Voices<DrumVoice>> {
+    Drum::ALL.iter().map(|&drum| {
+      Voices::new(count, VoiceStealing::Oldest, |i| DrumVoice::new(drum, voice_seed(seed, drum.index() * count + i), rate))
+    }).collect()
+  }
+}
+
+impl Modulable for DrumMachine {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    for voices in &mut self.drums {
+      voices.modulate(param, amount);
+    }
+  }
+}
+
+impl Instrument for DrumMachine {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    if let Some(drum) = Drum::from_note(note) {
+      // closed hats choke open ones
+      if drum == Drum::ClosedHat {
+        for voice in self.drums[Drum::OpenHat.index()].iter_mut() {
+          voice.choke();
+        }
+      }
+
+      self.drums[drum.index()].note_on(note, channel, expression);
+    }
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    for voices in &mut self.drums {
+      voices.note_off(channel);
+    }
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.drums.iter().any(|voices| voices.is_active())
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    let len = end.0 - start.0;
+
+    self.mixing_buffer.clear();
+    self.mixing_buffer.resize(len, 0.);
+
+    for voices in &mut self.drums {
+      for (sample, signal) in self.mixing_buffer.iter_mut().zip(voices.render(start, end)) {
+        *sample += signal;
+      }
+    }
+
+    &self.mixing_buffer
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, _: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    for x in left.iter_mut().chain(right.iter_mut()) {
+      *x = 0.;
+    }
+
+    for voices in &mut self.drums {
+      voices.mix_stereo(start, left, right);
+    }
+  }
+}
+
+// Build a one-shot envelope from durations known to be valid.
+fn ahd(attack: Time, hold: Time, decay: Time, rate: SampleRate) -> Breakpoints {
+  Breakpoints::ahd(attack, hold, decay, rate).expect("valid AHD")
+}
+
+// Recipe of a drum sound: a pitch-swept sine tone and filtered noise.
+struct Sound {
+  // pitch of the tone at the end of the sweep; unused by toms, which are tuned by their note
+  pitch: Hertz,
+  // pitch of the tone at the start of the sweep, relative to `pitch`
+  sweep_ratio: f32,
+  // time constant of the sweep
+  sweep_time: Time,
+  tone_level: f32,
+  tone_envelope: Breakpoints,
+  noise_level: f32,
+  noise_filter: Option<Biquad>,
+  noise_envelope: Breakpoints
+}
+
+impl Sound {
+  fn new(drum: Drum, rate: SampleRate) -> Self {
+    match drum {
+      Drum::Kick => Sound {
+        pitch: 48.,
+        sweep_ratio: 3.5,
+        sweep_time: 0.035,
+        tone_level: 1.,
+        tone_envelope: ahd(0.001, 0.02, 0.4, rate),
+        noise_level: 0.3,
+        noise_filter: Some(Biquad::low_pass(3000., 0.707, rate)),
+        noise_envelope: ahd(0., 0., 0.006, rate)
+      },
+
+      Drum::Snare => Sound {
+        pitch: 180.,
+        sweep_ratio: 1.4,
+        sweep_time: 0.02,
+        tone_level: 0.4,
+        tone_envelope: ahd(0.001, 0.005, 0.1, rate),
+        noise_level: 0.4,
+        noise_filter: Some(Biquad::high_pass(1500., 0.707, rate)),
+        noise_envelope: ahd(0.001, 0.01, 0.2, rate)
+      },
+
+      Drum::Clap => {
+        // three quick bursts, then the tail
+        let burst = |level| [
+          Breakpoint::new(0.001, level, Curve::Linear),
+          Breakpoint::new(0.009, 0.1, Curve::Exponential)
+        ];
+        let bursts = [burst(1.), burst(0.9), burst(0.8)];
+        let mut breakpoints: Vec<_> = bursts.iter().flat_map(|burst| burst.iter().cloned()).collect();
+        breakpoints.push(Breakpoint::new(0.001, 0.9, Curve::Linear));
+        breakpoints.push(Breakpoint::new(0.18, 0., Curve::Exponential));
+
+        Sound {
+          pitch: 0.,
+          sweep_ratio: 1.,
+          sweep_time: 0.,
+          tone_level: 0.,
+          tone_envelope: ahd(0., 0., 0., rate),
+          noise_level: 2.5,
+          noise_filter: Some(Biquad::band_pass(1200., 1.5, rate)),
+          noise_envelope: Breakpoints::new(&breakpoints, None, None, rate).expect("valid clap envelope")
+        }
+      }
+
+      Drum::ClosedHat | Drum::OpenHat => Sound {
+        pitch: 0.,
+        sweep_ratio: 1.,
+        sweep_time: 0.,
+        tone_level: 0.,
+        tone_envelope: ahd(0., 0., 0., rate),
+        noise_level: 0.5,
+        noise_filter: Some(Biquad::high_pass(7000., 0.707, rate)),
+        noise_envelope: if drum == Drum::OpenHat { ahd(0.001, 0.02, 0.4, rate) } else { ahd(0.001, 0., 0.05, rate) }
+      },
+
+      Drum::Tom => Sound {
+        pitch: 0.,
+        sweep_ratio: 1.6,
+        sweep_time: 0.04,
+        tone_level: 0.9,
+        tone_envelope: ahd(0.001, 0.01, 0.35, rate),
+        noise_level: 0.15,
+        noise_filter: Some(Biquad::low_pass(1000., 0.707, rate)),
+        noise_envelope: ahd(0.001, 0., 0.03, rate)
+      }
+    }
+  }
+}
+
+/// A voice of a `DrumMachine`: a single hit of a drum.
+pub struct DrumVoice {
+  drum: Drum,
+  sound: Sound,
+  noise: Noise,
+  step: f32,
+  freq: Hertz,
+  phase: f32,
+  // remaining part of the pitch sweep and its decay per sample
+  sweep: f32,
+  sweep_decay: f32,
+  // fade of a choked drum and its decay per sample
+  choke: f32,
+  choke_decay: f32,
+  choked: bool,
+  active: bool,
+  velocity: f32,
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  level: f32,
+  // envelope values of the block being rendered
+  tone_levels: Vec<f32>,
+  noise_levels: Vec<f32>,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl DrumVoice {
+  fn new(drum: Drum, seed: u32, rate: SampleRate) -> Self {
+    let sound = Sound::new(drum, rate);
+    let samples_per_sec = rate.0 as f32;
+    let sweep_decay = if sound.sweep_time > 0. { unsafe { expf32(-1. / (sound.sweep_time * samples_per_sec)) } } else { 0. };
+
+    DrumVoice {
+      drum,
+      sound,
+      noise: Noise::with_seed(NoiseColor::White, seed),
+      step: rate.step(),
+      freq: 0.,
+      phase: 0.,
+      sweep: 0.,
+      sweep_decay,
+      choke: 1.,
+      choke_decay: unsafe { expf32(-1. / (CHOKE_TIME * samples_per_sec)) },
+      choked: false,
+      active: false,
+      velocity: 1.,
+      pitch_factor: 1.,
+      gain: 1.,
+      level: 0.,
+      tone_levels: Vec::new(),
+      noise_levels: Vec::new(),
+      pan: (1., 1.)
+    }
+  }
+
+  /// Quickly fade the drum out.
+  fn choke(&mut self) {
+    if self.is_active() {
+      self.choked = true;
+    }
+  }
+
+  // Update the level and activity of the voice at a given sample time.
+  fn update_level(&mut self, t: SampleTime) {
+    let level = self.sound.tone_envelope.get(t).max(self.sound.noise_envelope.get(t));
+
+    self.level = level * self.choke;
+    self.active = self.sound.tone_envelope.is_active(t) || self.sound.noise_envelope.is_active(t);
+
+    if self.choked && self.choke < SILENCE {
+      self.active = false;
+    }
+  }
+}
+
+impl Voice for DrumVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.freq = if self.drum == Drum::Tom { tom_frequency(note) } else { self.sound.pitch };
+    self.velocity = expression.velocity;
+    self.pan = expression.pan_gains();
+    self.phase = 0.;
+    self.sweep = self.sound.sweep_ratio - 1.;
+    self.choke = 1.;
+    self.choked = false;
+
+    if let Some(ref mut filter) = self.sound.noise_filter {
+      filter.reset();
+    }
+
+    self.sound.tone_envelope.on(t);
+    self.sound.noise_envelope.on(t);
+    self.update_level(t);
+  }
+
+  // drums are one-shots: releasing a note lets the drum ring
+  fn release(&mut self, _: SampleTime) {}
+
+  fn is_active(&self) -> bool {
+    self.active
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let len = out.len();
+    let end = SampleTime(start.0 + len);
+    let dt = self.freq * self.pitch_factor * self.step;
+    let gain = self.gain * self.velocity;
+
+    self.tone_levels.resize(len, 0.);
+    self.noise_levels.resize(len, 0.);
+    self.sound.tone_envelope.render(start, &mut self.tone_levels);
+    self.sound.noise_envelope.render(start, &mut self.noise_levels);
+
+    let sound = &mut self.sound;
+
+    for ((sample, tone_level), noise_level) in out.iter_mut().zip(&self.tone_levels).zip(&self.noise_levels) {
+      let tone = sine_wave(self.phase) * sound.tone_level * tone_level;
+
+      self.phase = wrap_phase(self.phase + dt * (1. + self.sweep));
+      self.sweep *= self.sweep_decay;
+
+      let noise = self.noise.next_sample();
+      let noise = match sound.noise_filter {
+        Some(ref mut filter) => filter.process_sample(noise),
+        None => noise
+      };
+      let noise = noise * sound.noise_level * noise_level;
+
+      if self.choked {
+        self.choke *= self.choke_decay;
+      }
+
+      *sample += (tone + noise) * self.choke * gain;
+    }
+
+    self.update_level(end);
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::*;
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  fn active_voices(machine: &mut DrumMachine, drum: Drum) -> usize {
+    machine.drums[drum.index()].iter_mut().filter(|voice| voice.is_active()).count()
+  }
+
+  #[test]
+  fn general_midi_notes_play_drums() {
+    let drums = [
+      (B1, Some(Drum::Kick)),
+      (C2, Some(Drum::Kick)),
+      (DB2, None),
+      (D2, Some(Drum::Snare)),
+      (EB2, Some(Drum::Clap)),
+      (E2, Some(Drum::Snare)),
+      (F2, Some(Drum::Tom)),
+      (GB2, Some(Drum::ClosedHat)),
+      (G2, Some(Drum::Tom)),
+      (AB2, Some(Drum::ClosedHat)),
+      (A2, Some(Drum::Tom)),
+      (BB2, Some(Drum::OpenHat)),
+      (B2, Some(Drum::Tom)),
+      (C3, Some(Drum::Tom)),
+      (D3, Some(Drum::Tom)),
+      (C4, None)
+    ];
+
+    for &(note, drum) in &drums {
+      assert_eq!(Drum::from_note(note), drum, "note {}", note.number());
+    }
+  }
+
+  #[test]
+  fn drums_are_polyphonic_on_note_channels() {
+    let mut machine = DrumMachine::new(RATE).with_voices(2);
+
+    machine.note_on(C2, NoteChannel::new(0));
+    machine.note_on(B1, NoteChannel::new(1));
+    machine.note_on(D2, NoteChannel::new(2));
+
+    // drums don’t steal each other’s voices
+    assert_eq!(active_voices(&mut machine, Drum::Kick), 2);
+    assert_eq!(active_voices(&mut machine, Drum::Snare), 1);
+
+    // drums are one-shots: releasing their notes lets them ring
+    machine.note_off(NoteChannel::new(0));
+    machine.note_off(NoteChannel::new(1));
+    machine.get_samples(SampleTime(0), SampleTime(441));
+
+    assert_eq!(active_voices(&mut machine, Drum::Kick), 2);
+  }
+
+  #[test]
+  fn closed_hats_choke_open_hats() {
+    let mut choked = DrumMachine::new(RATE);
+    let mut ringing = DrumMachine::new(RATE);
+
+    choked.note_on(BB2, NoteChannel::new(0));
+    ringing.note_on(BB2, NoteChannel::new(0));
+    choked.get_samples(SampleTime(0), SampleTime(441));
+    ringing.get_samples(SampleTime(0), SampleTime(441));
+
+    choked.note_on(GB2, NoteChannel::new(1));
+    choked.get_samples(SampleTime(441), SampleTime(4410));
+    ringing.get_samples(SampleTime(441), SampleTime(4410));
+
+    assert_eq!(active_voices(&mut choked, Drum::OpenHat), 0);
+    assert_eq!(active_voices(&mut ringing, Drum::OpenHat), 1);
+  }
+}
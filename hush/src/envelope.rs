@@ -1,18 +1,214 @@
-//! ADSR envelopes and related types.
+//! Envelopes and related types.
+//!
+//! Two kinds of envelopes are available: the classic `ADSR` and `Breakpoints`, a general envelope
+//! made of an arbitrary list of segments. Both implement `Envelope`, so that they can be used
+//! interchangeably.
 
-use time::Time;
+use alloc::vec::Vec;
+use core::intrinsics::{expf32, fabsf32};
 
-/// State of an ADSR.  pub enum ADSRState {
+use time::{SampleRate, SampleTime, Time};
+
+// Tension used by `Curve::Exponential` and `Curve::Logarithmic`.
+const DEFAULT_TENSION: f32 = 5.;
+
+/// Envelope.
+///
+/// An envelope is a signal in `[0; 1]` that is switched on and off – typically when a note is
+/// pressed and released.
+///
+/// Envelopes are driven by sample time, so that they line up exactly with the audio they’re applied
+/// to. Switching them on or off can be scheduled at any sample time, including in the middle of the
+/// next block to render: the envelope changes state exactly at that sample.
+pub trait Envelope {
+  /// Switch on at the given sample time.
+  fn on(&mut self, t: SampleTime);
+
+  /// Switch off at the given sample time.
+  fn off(&mut self, t: SampleTime);
+
+  /// Get the value at a given sample time.
+  fn get(&self, t: SampleTime) -> f32;
+
+  /// Is the envelope still producing a signal at the given sample time?
+  fn is_active(&self, t: SampleTime) -> bool;
+
+  /// Render the envelope from `start` into `out`, one value per sample.
+  ///
+  /// Scheduled state changes that `out` covers take effect at their exact sample.
+  fn render(&mut self, start: SampleTime, out: &mut [f32]);
+
+  /// Multiply the samples of `buffer`, starting at `start`, by the envelope.
+  ///
+  /// Scheduled state changes that `buffer` covers take effect at their exact sample.
+  fn apply(&mut self, start: SampleTime, buffer: &mut [f32]);
+}
+
+/// State of an envelope.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ADSRState {
-  /// The ADSR was enabled.
+  /// The envelope was never switched on.
+  Idle,
+  /// The envelope was enabled.
+  ///
+  /// Also contains the sample time at which it was switched on and the level the attack starts
+  /// from.
+  On(SampleTime, f32),
+  /// The envelope was disabled.
   ///
-  /// Also contains the time at which it was switched on.
-  On(Time),
-  /// The ADSR was disabled.
+  /// Also contains the sample time at which it was switched off and the level the release starts
+  /// from.
+  Off(SampleTime, f32)
+}
+
+impl ADSRState {
+  // Sample time at which the state was entered.
+  fn time(&self) -> SampleTime {
+    match *self {
+      ADSRState::Idle => SampleTime(0),
+      ADSRState::On(t, _) | ADSRState::Off(t, _) => t
+    }
+  }
+}
+
+// State of an envelope along with a state scheduled to be entered in the future.
+#[derive(Clone, Debug)]
+struct Schedule {
+  state: ADSRState,
+  next_state: Option<ADSRState>
+}
+
+impl Schedule {
+  fn new() -> Self {
+    Schedule {
+      state: ADSRState::Idle,
+      next_state: None
+    }
+  }
+
+  fn state(&self) -> ADSRState {
+    self.next_state.unwrap_or(self.state)
+  }
+
+  fn state_at(&self, t: SampleTime) -> ADSRState {
+    match self.next_state {
+      Some(next) if next.time() <= t => next,
+      _ => self.state
+    }
+  }
+
+  // Switch on at `t` while the envelope is at `level`.
+  fn on(&mut self, t: SampleTime, level: f32, retrigger: Retrigger) {
+    let state = match (retrigger, self.state_at(t)) {
+      (Retrigger::Legato, ADSRState::On(..)) => return,
+      (Retrigger::Reset, _) => ADSRState::On(t, 0.),
+      _ => ADSRState::On(t, level)
+    };
+
+    self.schedule(state);
+  }
+
+  // Switch off at `t` while the envelope is at `level`.
+  fn off(&mut self, t: SampleTime, level: f32) {
+    self.schedule(ADSRState::Off(t, level));
+  }
+
+  // Schedule a new state; a state already scheduled is entered first.
+  fn schedule(&mut self, state: ADSRState) {
+    if let Some(next) = self.next_state.take() {
+      self.state = next;
+    }
+
+    self.next_state = Some(state);
+  }
+
+  // Enter the scheduled state if it’s due by `t`.
+  fn advance(&mut self, t: SampleTime) {
+    match self.next_state {
+      Some(next) if next.time() <= t => {
+        self.state = next;
+        self.next_state = None;
+      }
+
+      _ => ()
+    }
+  }
+}
+
+// Render `envelope` from `start` into `out`, one value per sample.
+fn render_values<E>(envelope: &E, start: SampleTime, out: &mut [f32]) where E: Envelope {
+  for (i, value) in out.iter_mut().enumerate() {
+    *value = envelope.get(SampleTime(start.0 + i));
+  }
+}
+
+// Multiply the samples of `buffer`, starting at `start`, by `envelope`.
+fn apply_values<E>(envelope: &E, start: SampleTime, buffer: &mut [f32]) where E: Envelope {
+  for (i, sample) in buffer.iter_mut().enumerate() {
+    *sample *= envelope.get(SampleTime(start.0 + i));
+  }
+}
+
+/// Shape of an envelope segment.
+///
+/// A curve maps the normalized progress through a segment, in `[0; 1]`, to the normalized progress
+/// from the level the segment starts at to the level it ends at, also in `[0; 1]`. Curves thus never
+/// overshoot, and an envelope made of curved segments stays in the range of its levels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+  /// Constant speed.
+  Linear,
+  /// Fast at first and slowing down, like a capacitor charging or discharging. This is the natural
+  /// shape of decays and releases.
+  Exponential,
+  /// Slow at first and speeding up.
+  Logarithmic,
+  /// Adjustable curve.
   ///
-  /// Also contains th etime at which it was switched off.
-  Off(Time)
+  /// Positive tensions are fast at first and slow down, negative ones are slow at first and speed
+  /// up; the bigger the absolute value, the more pronounced the curve. A tension of 0 is linear.
+  Tension(f32)
+}
+
+impl Curve {
+  /// Shape a normalized progress `x` in `[0; 1]`.
+  pub fn shape(&self, x: f32) -> f32 {
+    let tension = match *self {
+      Curve::Linear => return x,
+      Curve::Exponential => DEFAULT_TENSION,
+      Curve::Logarithmic => -DEFAULT_TENSION,
+      Curve::Tension(tension) => tension
+    };
+
+    unsafe {
+      if fabsf32(tension) < 1e-3 {
+        x
+      } else if tension < 0. {
+        // mirror of the positive tension, which doesn’t overflow for steep curves
+        1. - Curve::Tension(-tension).shape(1. - x)
+      } else {
+        (1. - expf32(-tension * x)) / (1. - expf32(-tension))
+      }
+    }
+  }
+}
+
+impl Default for Curve {
+  fn default() -> Self {
+    Curve::Linear
+  }
+}
+
+/// What an envelope does when it’s switched on while it’s still producing a signal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Retrigger {
+  /// Restart the attack from 0.
+  Reset,
+  /// Restart the attack from the current level.
+  Continue,
+  /// Don’t retrigger if the envelope is still on; if it’s releasing, restart the attack from the
+  /// current level.
+  Legato
 }
 
 /// A normalized ADSR (Attack–Decay–Sustain–Release) envelope.
@@ -21,70 +217,606 @@ pub enum ADSRState {
 /// modulation, etc.
 ///
 /// The minimal value an ADSR gives you is 0. The maximal value an ADSR envelope gives you is 1.
+///
+/// When switched off, the release starts from whatever level the envelope is at, even if it was
+/// still attacking or decaying. What happens when switching on an envelope that’s still producing a
+/// signal depends on its `Retrigger` mode.
+///
+/// The attack, decay and release segments are linear by default and can be given other `Curve`s.
+#[derive(Clone, Debug)]
 pub struct ADSR {
-  attack: Time,
-  decay: Time,
+  // durations are in samples
+  attack: f32,
+  decay: f32,
   sustain: f32,
-  release: Time,
-  state: ADSRState
+  release: f32,
+  schedule: Schedule,
+  retrigger: Retrigger,
+  attack_curve: Curve,
+  decay_curve: Curve,
+  release_curve: Curve,
+  rate: SampleRate
 }
 
 impl ADSR {
-  pub fn new(attack: Time, decay: Time, sustain: f32, release: Time) -> Option<Self> {
+  pub fn new(attack: Time, decay: Time, sustain: f32, release: Time, rate: SampleRate) -> Option<Self> {
     if attack <= 0. || decay <= 0. || sustain < 0. || release < 0. {
       return None;
     }
 
+    let samples_per_sec = rate.0 as f32;
+
     Some(Self {
-      attack,
-      decay,
+      attack: attack * samples_per_sec,
+      decay: decay * samples_per_sec,
       sustain,
-      release,
-      state: ADSRState::Off(-1. / 0.) // -Inf should be enough for anyone trying to release :D
+      release: release * samples_per_sec,
+      schedule: Schedule::new(),
+      retrigger: Retrigger::Reset,
+      attack_curve: Curve::Linear,
+      decay_curve: Curve::Linear,
+      release_curve: Curve::Linear,
+      rate
     })
   }
 
-  // Switch on.
-  pub fn on(&mut self, t: Time) {
-    self.state = ADSRState::On(t);
+  // Create an ADSR from durations known to be valid.
+  pub(crate) fn valid(attack: Time, decay: Time, sustain: f32, release: Time, rate: SampleRate) -> Self {
+    Self::new(attack, decay, sustain, release, rate).expect("valid ADSR")
   }
 
-  // Switch off.
-  pub fn off(&mut self, t: Time) {
-    self.state = ADSRState::Off(t);
+  /// Change the curves of the attack, decay and release segments.
+  pub fn with_curves(mut self, attack: Curve, decay: Curve, release: Curve) -> Self {
+    self.attack_curve = attack;
+    self.decay_curve = decay;
+    self.release_curve = release;
+    self
   }
 
-  // Get the state of the envelope.
+  /// Change the retrigger mode (`Retrigger::Reset` by default).
+  pub fn with_retrigger(mut self, retrigger: Retrigger) -> Self {
+    self.retrigger = retrigger;
+    self
+  }
+
+  /// Retrigger mode.
+  pub fn retrigger(&self) -> Retrigger {
+    self.retrigger
+  }
+
+  /// Sample rate used to convert durations into samples.
+  pub fn sample_rate(&self) -> SampleRate {
+    self.rate
+  }
+
+  /// Get the state of the envelope, including a state scheduled in the future.
   pub fn state(&self) -> ADSRState {
-    self.state
+    self.schedule.state()
+  }
+
+  /// Get the state the envelope is in at a given sample time.
+  pub fn state_at(&self, t: SampleTime) -> ADSRState {
+    self.schedule.state_at(t)
   }
+}
+
+impl Envelope for ADSR {
+  fn on(&mut self, t: SampleTime) {
+    let level = self.get(t);
+    self.schedule.on(t, level, self.retrigger);
+  }
+
+  fn off(&mut self, t: SampleTime) {
+    // the release starts from the current level
+    let level = self.get(t);
+    self.schedule.off(t, level);
+  }
+
+  fn get(&self, t: SampleTime) -> f32 {
+    match self.state_at(t) {
+      ADSRState::Idle => 0.,
 
-  // Get the current value based on the current time.
-  pub fn get(&self, t: Time) -> f32 {
-    match self.state {
-      ADSRState::On(t_0) => {
-        let attack_ = t_0 + self.attack;
+      ADSRState::On(t_0, level) => {
+        let nt = t.0.saturating_sub(t_0.0) as f32;
 
-        if t <= attack_ { // attacking
-          return (t - t_0) / self.attack;
+        if nt <= self.attack { // attacking
+          return level + (1. - level) * self.attack_curve.shape(nt / self.attack);
         }
 
-        let decay_ = attack_ + self.decay;
-        
-        if t <= t_0 + decay_{ // decaying
-          let nt = (t - attack_) / self.decay;
+        let nt = nt - self.attack;
+
+        if nt <= self.decay { // decaying
+          let nt = self.decay_curve.shape(nt / self.decay);
           1. + nt * (self.sustain - 1.)
         } else { // sustaining
           self.sustain
         }
       }
 
-      ADSRState::Off(t_0) => {
+      ADSRState::Off(t_0, level) => {
         // release only possible here
-        let nt = (t - t_0) / self.release;
-        let q = (1. - nt) * self.sustain;
+        let nt = t.0.saturating_sub(t_0.0) as f32 / self.release;
+        let q = (1. - self.release_curve.shape(nt.min(1.))) * level;
         q.max(0.) // ensure we don’t get weird negative values if we forget to switch the ADSR off
       }
     }
   }
+
+  fn is_active(&self, t: SampleTime) -> bool {
+    match self.state_at(t) {
+      ADSRState::Idle => false,
+      ADSRState::On(..) => true,
+      ADSRState::Off(..) => self.get(t) > 0.
+    }
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [f32]) {
+    render_values(self, start, out);
+    self.schedule.advance(SampleTime(start.0 + out.len()));
+  }
+
+  fn apply(&mut self, start: SampleTime, buffer: &mut [f32]) {
+    apply_values(self, start, buffer);
+    self.schedule.advance(SampleTime(start.0 + buffer.len()));
+  }
+}
+
+/// A breakpoint: the end of an envelope segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+  /// Time it takes to reach `level` from the previous breakpoint.
+  pub duration: Time,
+  /// Level reached at the end of the segment.
+  pub level: f32,
+  /// Shape of the segment.
+  pub curve: Curve
+}
+
+impl Breakpoint {
+  pub fn new(duration: Time, level: f32, curve: Curve) -> Self {
+    Breakpoint { duration, level, curve }
+  }
+}
+
+// A breakpoint with its duration in samples.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+  duration: f32,
+  level: f32,
+  curve: Curve
+}
+
+/// A multi-stage envelope made of breakpoints.
+///
+/// When switched on, the envelope goes through its breakpoints, one after the other. Each segment
+/// goes from the level of the previous breakpoint – or the level the envelope starts at for the
+/// first one – to the level of its breakpoint, following the breakpoint’s curve.
+///
+/// While the envelope is on:
+///
+///   - If it has a loop region, it plays its breakpoints up to the end of the loop, then keeps on
+///     looping from the first breakpoint of the loop to the last one.
+///   - Otherwise, if it has a sustain point, it stops at that breakpoint and holds its level.
+///   - Otherwise, it plays all of its breakpoints and holds the level of the last one. Switching it
+///     off has no effect: that is a one-shot envelope, such as `Breakpoints::ahd`.
+///
+/// When switched off, the envelope plays the breakpoints following the loop region or the sustain
+/// point, starting from the level it’s at. It goes to 0 right away if there are no such breakpoints.
+///
+/// Levels should lie in `[0; 1]` for the envelope to be normalized.
+#[derive(Clone, Debug)]
+pub struct Breakpoints {
+  segments: Vec<Segment>,
+  // index of the last segment played while on, if any
+  hold: Option<usize>,
+  looping: Option<(usize, usize)>,
+  schedule: Schedule,
+  retrigger: Retrigger,
+  rate: SampleRate
+}
+
+impl Breakpoints {
+  /// Create an envelope out of breakpoints, an optional sustain point and an optional loop region.
+  ///
+  /// The sustain point is the index of a breakpoint. The loop region contains the indices of the
+  /// first and last breakpoints of the loop.
+  ///
+  /// Return `None` if there are no breakpoints, if a duration is negative, if the loop region has
+  /// no duration or if an index is out of range.
+  pub fn new(
+    breakpoints: &[Breakpoint],
+    sustain: Option<usize>,
+    looping: Option<(usize, usize)>,
+    rate: SampleRate
+  ) -> Option<Self> {
+    let len = breakpoints.len();
+
+    if len == 0 || breakpoints.iter().any(|bp| bp.duration < 0.) {
+      return None;
+    }
+
+    if sustain.map_or(false, |i| i >= len) {
+      return None;
+    }
+
+    if let Some((first, last)) = looping {
+      if first > last || last >= len {
+        return None;
+      }
+
+      // a loop without duration would never end
+      if breakpoints[first ..= last].iter().all(|bp| bp.duration <= 0.) {
+        return None;
+      }
+    }
+
+    let samples_per_sec = rate.0 as f32;
+    let segments = breakpoints.iter().map(|bp| Segment {
+      duration: bp.duration * samples_per_sec,
+      level: bp.level,
+      curve: bp.curve
+    }).collect();
+
+    Some(Breakpoints {
+      segments,
+      hold: looping.map(|(_, last)| last).or(sustain),
+      looping,
+      schedule: Schedule::new(),
+      retrigger: Retrigger::Reset,
+      rate
+    })
+  }
+
+  /// A DAHDSR (Delay–Attack–Hold–Decay–Sustain–Release) envelope.
+  ///
+  /// Return `None` if a duration is negative.
+  pub fn dahdsr(
+    delay: Time,
+    attack: Time,
+    hold: Time,
+    decay: Time,
+    sustain: f32,
+    release: Time,
+    rate: SampleRate
+  ) -> Option<Self> {
+    let breakpoints = [
+      Breakpoint::new(delay, 0., Curve::Linear),
+      Breakpoint::new(attack, 1., Curve::Linear),
+      Breakpoint::new(hold, 1., Curve::Linear),
+      Breakpoint::new(decay, sustain, Curve::Linear),
+      Breakpoint::new(release, 0., Curve::Linear)
+    ];
+
+    Self::new(&breakpoints, Some(3), None, rate)
+  }
+
+  /// A one-shot AHD (Attack–Hold–Decay) envelope, typically used for drums.
+  ///
+  /// Return `None` if a duration is negative.
+  pub fn ahd(attack: Time, hold: Time, decay: Time, rate: SampleRate) -> Option<Self> {
+    let breakpoints = [
+      Breakpoint::new(attack, 1., Curve::Linear),
+      Breakpoint::new(hold, 1., Curve::Linear),
+      Breakpoint::new(decay, 0., Curve::Exponential)
+    ];
+
+    Self::new(&breakpoints, None, None, rate)
+  }
+
+  /// Change the retrigger mode (`Retrigger::Reset` by default).
+  pub fn with_retrigger(mut self, retrigger: Retrigger) -> Self {
+    self.retrigger = retrigger;
+    self
+  }
+
+  /// Retrigger mode.
+  pub fn retrigger(&self) -> Retrigger {
+    self.retrigger
+  }
+
+  /// Sample rate used to convert durations into samples.
+  pub fn sample_rate(&self) -> SampleRate {
+    self.rate
+  }
+
+  /// Get the state of the envelope, including a state scheduled in the future.
+  pub fn state(&self) -> ADSRState {
+    self.schedule.state()
+  }
+
+  /// Get the state the envelope is in at a given sample time.
+  pub fn state_at(&self, t: SampleTime) -> ADSRState {
+    self.schedule.state_at(t)
+  }
+
+  // Segments played while the envelope is on.
+  fn on_segments(&self) -> &[Segment] {
+    match self.hold {
+      Some(last) => &self.segments[..= last],
+      None => &self.segments
+    }
+  }
+
+  // Segments played once the envelope is switched off.
+  fn off_segments(&self) -> &[Segment] {
+    match self.hold {
+      Some(last) => &self.segments[last + 1 ..],
+      None => &[]
+    }
+  }
+
+  // Value while on, `nt` samples after having been switched on at `level`.
+  fn get_on(&self, nt: f32, level: f32) -> f32 {
+    match walk(self.on_segments(), level, nt) {
+      Ok(value) => value,
+
+      Err((nt, level)) => {
+        match self.looping {
+          Some((first, last)) => {
+            let looped = &self.segments[first ..= last];
+            let period = looped.iter().fold(0., |d, segment| d + segment.duration);
+
+            walk(looped, level, nt % period).unwrap_or(level)
+          }
+
+          None => level
+        }
+      }
+    }
+  }
+}
+
+// Walk through `segments` starting at `level`, `nt` samples in.
+//
+// Return the value if `nt` lies in the segments, or how many samples past their end it is along
+// with the level of the last one.
+fn walk(segments: &[Segment], mut level: f32, mut nt: f32) -> Result<f32, (f32, f32)> {
+  for segment in segments {
+    if nt < segment.duration {
+      return Ok(level + (segment.level - level) * segment.curve.shape(nt / segment.duration));
+    }
+
+    nt -= segment.duration;
+    level = segment.level;
+  }
+
+  Err((nt, level))
+}
+
+impl Envelope for Breakpoints {
+  fn on(&mut self, t: SampleTime) {
+    let level = self.get(t);
+    self.schedule.on(t, level, self.retrigger);
+  }
+
+  fn off(&mut self, t: SampleTime) {
+    // one-shot envelopes ignore note-off
+    if self.hold.is_none() {
+      return;
+    }
+
+    // the release starts from the current level
+    let level = self.get(t);
+    self.schedule.off(t, level);
+  }
+
+  fn get(&self, t: SampleTime) -> f32 {
+    match self.state_at(t) {
+      ADSRState::Idle => 0.,
+
+      ADSRState::On(t_0, level) => {
+        let nt = t.0.saturating_sub(t_0.0) as f32;
+        self.get_on(nt, level)
+      }
+
+      ADSRState::Off(t_0, level) => {
+        let nt = t.0.saturating_sub(t_0.0) as f32;
+        let segments = self.off_segments();
+
+        if segments.is_empty() {
+          return 0.;
+        }
+
+        walk(segments, level, nt).unwrap_or_else(|(_, level)| level)
+      }
+    }
+  }
+
+  fn is_active(&self, t: SampleTime) -> bool {
+    match self.state_at(t) {
+      ADSRState::Idle => false,
+
+      ADSRState::On(..) if self.hold.is_some() => true,
+
+      ADSRState::On(t_0, level) => {
+        let nt = t.0.saturating_sub(t_0.0) as f32;
+        walk(&self.segments, level, nt).is_ok() || self.get(t) > 0.
+      }
+
+      ADSRState::Off(t_0, level) => {
+        let nt = t.0.saturating_sub(t_0.0) as f32;
+        walk(self.off_segments(), level, nt).is_ok() || self.get(t) > 0.
+      }
+    }
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [f32]) {
+    render_values(self, start, out);
+    self.schedule.advance(SampleTime(start.0 + out.len()));
+  }
+
+  fn apply(&mut self, start: SampleTime, buffer: &mut [f32]) {
+    apply_values(self, start, buffer);
+    self.schedule.advance(SampleTime(start.0 + buffer.len()));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATE: SampleRate = SampleRate(1000);
+
+  #[test]
+  fn adsr_reaches_sustain_after_attack_and_decay() {
+    // 10 samples of attack, 20 samples of decay
+    let mut adsr = ADSR::new(0.01, 0.02, 0.5, 0.1, RATE).unwrap();
+
+    adsr.on(SampleTime(5));
+
+    assert_eq!(adsr.get(SampleTime(5)), 0.);
+    assert_eq!(adsr.get(SampleTime(15)), 1.);
+    assert!(adsr.get(SampleTime(34)) > 0.5);
+    assert_eq!(adsr.get(SampleTime(35)), 0.5);
+    assert_eq!(adsr.get(SampleTime(1000)), 0.5);
+  }
+
+  #[test]
+  fn adsr_renders_state_changes_at_their_sample() {
+    let mut adsr = ADSR::new(0.01, 0.02, 0.5, 0.1, RATE).unwrap();
+    let mut out = [1.; 8];
+
+    adsr.on(SampleTime(3));
+    adsr.render(SampleTime(0), &mut out);
+
+    assert_eq!(out[.. 4], [0., 0., 0., 0.]);
+    assert_eq!(out[4], 0.1);
+  }
+
+  #[test]
+  fn adsr_releases_from_current_level() {
+    let mut adsr = ADSR::new(0.01, 0.02, 0.5, 0.1, RATE).unwrap();
+
+    // switched off halfway through the attack
+    adsr.on(SampleTime(0));
+    adsr.off(SampleTime(5));
+
+    assert_eq!(adsr.get(SampleTime(5)), 0.5);
+    assert_eq!(adsr.get(SampleTime(55)), 0.25);
+    assert_eq!(adsr.get(SampleTime(105)), 0.);
+    assert!(!adsr.is_active(SampleTime(105)));
+  }
+
+  #[test]
+  fn adsr_retriggers_from_current_level() {
+    let reset = ADSR::new(0.01, 0.02, 0.5, 0.1, RATE).unwrap();
+    let mut cont = reset.clone().with_retrigger(Retrigger::Continue);
+    let mut legato = reset.clone().with_retrigger(Retrigger::Legato);
+    let mut reset = reset;
+
+    for adsr in [&mut reset, &mut cont, &mut legato].iter_mut() {
+      adsr.on(SampleTime(0));
+      adsr.on(SampleTime(5));
+    }
+
+    assert_eq!(reset.get(SampleTime(5)), 0.);
+    assert_eq!(cont.get(SampleTime(5)), 0.5);
+    assert_eq!(cont.get(SampleTime(10)), 0.75);
+    assert_eq!(legato.get(SampleTime(10)), 1.);
+  }
+
+  #[test]
+  fn tension_curves_end_exactly_at_0_and_1() {
+    let curves = [
+      Curve::Exponential,
+      Curve::Logarithmic,
+      Curve::Tension(0.5),
+      Curve::Tension(-12.),
+      Curve::Tension(15.),
+      Curve::Tension(-200.),
+      Curve::Tension(200.)
+    ];
+
+    for curve in &curves {
+      assert_eq!(curve.shape(0.), 0.);
+      assert_eq!(curve.shape(1.), 1.);
+
+      let x = curve.shape(0.1);
+      assert!(x >= 0. && x <= 1., "{:?} gives {}", curve, x);
+    }
+
+    assert!(Curve::Tension(15.).shape(0.1) > 0.5);
+    assert!(Curve::Tension(-15.).shape(0.9) < 0.5);
+  }
+
+  #[test]
+  fn breakpoints_loop_while_on() {
+    let breakpoints = [
+      Breakpoint::new(0.01, 1., Curve::Linear),
+      Breakpoint::new(0.01, 0.5, Curve::Linear),
+      Breakpoint::new(0.01, 1., Curve::Linear),
+      Breakpoint::new(0.01, 0., Curve::Linear)
+    ];
+    let mut env = Breakpoints::new(&breakpoints, None, Some((1, 2)), RATE).unwrap();
+
+    env.on(SampleTime(0));
+
+    assert_eq!(env.get(SampleTime(20)), 0.5);
+
+    // the loop lasts 20 samples
+    for t in 30 .. 100 {
+      assert!((env.get(SampleTime(t)) - env.get(SampleTime(t - 20))).abs() < 1e-6);
+    }
+
+    assert!(env.is_active(SampleTime(10_000)));
+
+    // released from the end of the loop
+    env.off(SampleTime(100));
+
+    assert_eq!(env.get(SampleTime(110)), 0.);
+    assert!(!env.is_active(SampleTime(110)));
+  }
+
+  #[test]
+  fn breakpoints_hold_sustain_point() {
+    let breakpoints = [
+      Breakpoint::new(0.01, 1., Curve::Linear),
+      Breakpoint::new(0.01, 0.5, Curve::Linear),
+      Breakpoint::new(0.01, 0., Curve::Linear)
+    ];
+    let mut env = Breakpoints::new(&breakpoints, Some(1), None, RATE).unwrap();
+
+    env.on(SampleTime(0));
+
+    assert_eq!(env.get(SampleTime(20)), 0.5);
+    assert_eq!(env.get(SampleTime(1000)), 0.5);
+
+    env.off(SampleTime(1000));
+
+    assert_eq!(env.get(SampleTime(1005)), 0.25);
+    assert!(!env.is_active(SampleTime(1010)));
+  }
+
+  #[test]
+  fn one_shot_breakpoints_ignore_off() {
+    let mut env = Breakpoints::ahd(0.01, 0.01, 0.01, RATE).unwrap();
+
+    env.on(SampleTime(0));
+    env.off(SampleTime(5));
+
+    assert_eq!(env.get(SampleTime(15)), 1.);
+    assert!(env.is_active(SampleTime(25)));
+    assert!(!env.is_active(SampleTime(30)));
+  }
+
+  #[test]
+  fn breakpoints_reject_invalid_envelopes() {
+    let breakpoints = [
+      Breakpoint::new(0.01, 1., Curve::Linear),
+      Breakpoint::new(0., 0.5, Curve::Linear),
+      Breakpoint::new(0., 0.5, Curve::Linear),
+      Breakpoint::new(0.01, 0., Curve::Linear)
+    ];
+
+    // loops without duration
+    assert!(Breakpoints::new(&breakpoints, None, Some((1, 2)), RATE).is_none());
+    assert!(Breakpoints::new(&breakpoints, None, Some((1, 1)), RATE).is_none());
+
+    assert!(Breakpoints::new(&breakpoints, None, Some((0, 2)), RATE).is_some());
+    assert!(Breakpoints::new(&[], None, None, RATE).is_none());
+    assert!(Breakpoints::new(&breakpoints, Some(4), None, RATE).is_none());
+    assert!(Breakpoints::new(&breakpoints, None, Some((2, 1)), RATE).is_none());
+    assert!(Breakpoints::new(&breakpoints, None, Some((3, 4)), RATE).is_none());
+    assert!(Breakpoints::new(&[Breakpoint::new(-1., 1., Curve::Linear)], None, None, RATE).is_none());
+  }
 }
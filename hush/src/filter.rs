@@ -0,0 +1,576 @@
+//! Resonant filters.
+//!
+//! Filters process blocks of samples in place. Their cutoff frequency can be changed at any time –
+//! changes are then smoothed over a few milliseconds so that they never produce zipper noise – or
+//! modulated per sample, in which case the modulation is followed exactly.
+//!
+//! Two kinds of filters are available:
+//!
+//!   - `StateVariable`, a resonant state-variable filter giving low-pass, high-pass, band-pass and
+//!     notch outputs at once. It behaves very well under heavy modulation, making it the filter of
+//!     choice for synth voices.
+//!   - `Biquad`, the classic RBJ biquads: low-pass, high-pass, band-pass, peaking and shelves.
+
+use core::f32::consts::PI;
+use core::intrinsics::{cosf32, expf32, exp2f32, powf32, sinf32};
+
+use hertz::Hertz;
+use modulation::{CUTOFF, Modulable, RESONANCE};
+use sample::Sample;
+use time::{SampleRate, Time};
+
+// Time it takes to smooth a parameter change.
+const SMOOTHING_TIME: Time = 0.005;
+
+// Lowest cutoff frequency.
+const MIN_CUTOFF: Hertz = 10.;
+
+// Highest cutoff frequency, relative to the sample rate.
+const MAX_CUTOFF_RATIO: f32 = 0.49;
+
+// A parameter smoothed with a one-pole low-pass filter.
+#[derive(Clone, Debug)]
+struct Smoothed {
+  current: f32,
+  target: f32,
+  coef: f32
+}
+
+impl Smoothed {
+  fn new(value: f32, rate: SampleRate) -> Self {
+    Smoothed {
+      current: value,
+      target: value,
+      coef: 1. - unsafe { expf32(-1. / (SMOOTHING_TIME * rate.0 as f32)) }
+    }
+  }
+
+  // Move towards the target; return whether the value changed.
+  #[inline(always)]
+  fn next(&mut self) -> bool {
+    if self.current == self.target {
+      return false;
+    }
+
+    self.current += (self.target - self.current) * self.coef;
+
+    // snap when close enough so that the coefficients stop being recomputed
+    if (self.target - self.current) * (self.target - self.current) < 1e-6 {
+      self.current = self.target;
+    }
+
+    true
+  }
+
+  // Jump to a value, without smoothing.
+  #[inline(always)]
+  fn set(&mut self, value: f32) {
+    self.current = value;
+    self.target = value;
+  }
+}
+
+// Clamp a cutoff frequency to what can be represented at a given rate.
+fn clamp_cutoff(cutoff: Hertz, rate: SampleRate) -> Hertz {
+  cutoff.max(MIN_CUTOFF).min(rate.0 as f32 * MAX_CUTOFF_RATIO)
+}
+
+/// Output of a state-variable filter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SvfMode {
+  LowPass,
+  HighPass,
+  BandPass,
+  Notch
+}
+
+/// All outputs of a state-variable filter for a single sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SvfOutput {
+  pub low: Sample,
+  pub high: Sample,
+  pub band: Sample,
+  pub notch: Sample
+}
+
+/// A resonant state-variable filter.
+///
+/// This is a topology-preserving (trapezoidal) state-variable filter, which stays stable and free of
+/// artifacts when its cutoff is modulated at audio rate.
+///
+/// The resonance lies in `[0; 1[`: 0 is a gentle, non-resonant filter and the filter gets closer to
+/// self-oscillation as the resonance gets closer to 1.
+///
+/// It can be modulated (see `Modulable`) on `modulation::CUTOFF` and `modulation::RESONANCE`.
+#[derive(Clone, Debug)]
+pub struct StateVariable {
+  mode: SvfMode,
+  cutoff: Smoothed,
+  resonance: f32,
+  // modulations
+  cutoff_factor: f32,
+  resonance_offset: f32,
+  rate: SampleRate,
+  // coefficients
+  k: f32,
+  a1: f32,
+  a2: f32,
+  a3: f32,
+  // state
+  ic1eq: f32,
+  ic2eq: f32
+}
+
+impl StateVariable {
+  pub fn new(mode: SvfMode, cutoff: Hertz, resonance: f32, rate: SampleRate) -> Self {
+    let cutoff = clamp_cutoff(cutoff, rate);
+
+    let mut filter = StateVariable {
+      mode,
+      cutoff: Smoothed::new(cutoff, rate),
+      resonance,
+      cutoff_factor: 1.,
+      resonance_offset: 0.,
+      rate,
+      k: 0.,
+      a1: 0.,
+      a2: 0.,
+      a3: 0.,
+      ic1eq: 0.,
+      ic2eq: 0.
+    };
+
+    filter.update_coefficients();
+    filter
+  }
+
+  /// Output used by `StateVariable::process`.
+  pub fn mode(&self) -> SvfMode {
+    self.mode
+  }
+
+  /// Change the output used by `StateVariable::process`.
+  pub fn set_mode(&mut self, mode: SvfMode) {
+    self.mode = mode;
+  }
+
+  /// Cutoff frequency the filter is heading to.
+  pub fn cutoff(&self) -> Hertz {
+    self.cutoff.target
+  }
+
+  /// Change the cutoff frequency; the change is smoothed.
+  pub fn set_cutoff(&mut self, cutoff: Hertz) {
+    self.cutoff.target = clamp_cutoff(cutoff, self.rate);
+  }
+
+  /// Resonance.
+  pub fn resonance(&self) -> f32 {
+    self.resonance
+  }
+
+  /// Change the resonance.
+  pub fn set_resonance(&mut self, resonance: f32) {
+    self.resonance = resonance;
+    self.update_coefficients();
+  }
+
+  /// Clear the state of the filter.
+  pub fn reset(&mut self) {
+    self.ic1eq = 0.;
+    self.ic2eq = 0.;
+  }
+
+  /// Filter a single sample and get all the outputs.
+  #[inline(always)]
+  pub fn process_sample(&mut self, x: Sample) -> SvfOutput {
+    if self.cutoff.next() {
+      self.update_coefficients();
+    }
+
+    let v3 = x - self.ic2eq;
+    let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+    let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+
+    self.ic1eq = 2. * v1 - self.ic1eq;
+    self.ic2eq = 2. * v2 - self.ic2eq;
+
+    SvfOutput {
+      low: v2,
+      high: x - self.k * v1 - v2,
+      band: v1,
+      notch: x - self.k * v1
+    }
+  }
+
+  /// Filter a block of samples in place.
+  pub fn process(&mut self, buffer: &mut [Sample]) {
+    for sample in buffer {
+      let output = self.process_sample(*sample);
+      *sample = self.select(output);
+    }
+  }
+
+  /// Filter a block of samples in place, with a cutoff frequency per sample.
+  ///
+  /// The cutoffs aren’t smoothed, so that fast envelopes and audio-rate modulations keep their
+  /// shape. `cutoffs` must be as long as `buffer`.
+  pub fn process_modulated(&mut self, buffer: &mut [Sample], cutoffs: &[Hertz]) {
+    assert_eq!(buffer.len(), cutoffs.len());
+
+    for (sample, &cutoff) in buffer.iter_mut().zip(cutoffs) {
+      self.cutoff.set(clamp_cutoff(cutoff, self.rate));
+      self.update_coefficients();
+
+      let output = self.process_sample(*sample);
+      *sample = self.select(output);
+    }
+  }
+
+  fn select(&self, output: SvfOutput) -> Sample {
+    match self.mode {
+      SvfMode::LowPass => output.low,
+      SvfMode::HighPass => output.high,
+      SvfMode::BandPass => output.band,
+      SvfMode::Notch => output.notch
+    }
+  }
+
+  fn update_coefficients(&mut self) {
+    let cutoff = clamp_cutoff(self.cutoff.current * self.cutoff_factor, self.rate);
+    let resonance = (self.resonance + self.resonance_offset).max(0.).min(0.999);
+    let w = PI * cutoff / self.rate.0 as f32;
+    let g = unsafe { sinf32(w) / cosf32(w) };
+
+    self.k = 2. - 2. * resonance;
+    self.a1 = 1. / (1. + g * (g + self.k));
+    self.a2 = g * self.a1;
+    self.a3 = g * self.a2;
+  }
+}
+
+impl Modulable for StateVariable {
+  /// The cutoff is modulated in octaves; the resonance is offset.
+  fn modulate(&mut self, param: &str, amount: f32) {
+    match param {
+      CUTOFF => self.cutoff_factor = unsafe { exp2f32(amount) },
+      RESONANCE => self.resonance_offset = amount,
+      _ => return
+    }
+
+    self.update_coefficients();
+  }
+}
+
+/// Kind of biquad filter.
+///
+/// Gains are in decibels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BiquadKind {
+  LowPass,
+  HighPass,
+  BandPass,
+  Peaking { gain: f32 },
+  LowShelf { gain: f32 },
+  HighShelf { gain: f32 }
+}
+
+/// An RBJ biquad filter.
+///
+/// The frequency is the cutoff of pass filters, the center of peaking filters and the midpoint of
+/// shelves. `q` sets the resonance of pass filters, the bandwidth of peaking filters and the slope of
+/// shelves (0.707 is a good default for all of them).
+///
+/// It can be modulated (see `Modulable`) on `modulation::CUTOFF` and `modulation::RESONANCE`, the
+/// latter offsetting `q`.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+  kind: BiquadKind,
+  freq: Smoothed,
+  q: f32,
+  // modulations
+  freq_factor: f32,
+  q_offset: f32,
+  rate: SampleRate,
+  // normalized coefficients
+  b0: f32,
+  b1: f32,
+  b2: f32,
+  a1: f32,
+  a2: f32,
+  // state (transposed direct form II)
+  z1: f32,
+  z2: f32
+}
+
+impl Biquad {
+  pub fn new(kind: BiquadKind, freq: Hertz, q: f32, rate: SampleRate) -> Self {
+    let freq = clamp_cutoff(freq, rate);
+
+    let mut filter = Biquad {
+      kind,
+      freq: Smoothed::new(freq, rate),
+      q,
+      freq_factor: 1.,
+      q_offset: 0.,
+      rate,
+      b0: 1.,
+      b1: 0.,
+      b2: 0.,
+      a1: 0.,
+      a2: 0.,
+      z1: 0.,
+      z2: 0.
+    };
+
+    filter.update_coefficients();
+    filter
+  }
+
+  pub fn low_pass(cutoff: Hertz, q: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::LowPass, cutoff, q, rate)
+  }
+
+  pub fn high_pass(cutoff: Hertz, q: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::HighPass, cutoff, q, rate)
+  }
+
+  pub fn band_pass(center: Hertz, q: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::BandPass, center, q, rate)
+  }
+
+  pub fn peaking(center: Hertz, q: f32, gain: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::Peaking { gain }, center, q, rate)
+  }
+
+  pub fn low_shelf(freq: Hertz, q: f32, gain: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::LowShelf { gain }, freq, q, rate)
+  }
+
+  pub fn high_shelf(freq: Hertz, q: f32, gain: f32, rate: SampleRate) -> Self {
+    Self::new(BiquadKind::HighShelf { gain }, freq, q, rate)
+  }
+
+  /// Kind of filter.
+  pub fn kind(&self) -> BiquadKind {
+    self.kind
+  }
+
+  /// Change the kind of filter.
+  pub fn set_kind(&mut self, kind: BiquadKind) {
+    self.kind = kind;
+    self.update_coefficients();
+  }
+
+  /// Frequency the filter is heading to.
+  pub fn frequency(&self) -> Hertz {
+    self.freq.target
+  }
+
+  /// Change the frequency; the change is smoothed.
+  pub fn set_frequency(&mut self, freq: Hertz) {
+    self.freq.target = clamp_cutoff(freq, self.rate);
+  }
+
+  /// Q factor.
+  pub fn q(&self) -> f32 {
+    self.q
+  }
+
+  /// Change the Q factor.
+  pub fn set_q(&mut self, q: f32) {
+    self.q = q;
+    self.update_coefficients();
+  }
+
+  /// Clear the state of the filter.
+  pub fn reset(&mut self) {
+    self.z1 = 0.;
+    self.z2 = 0.;
+  }
+
+  /// Filter a single sample.
+  #[inline(always)]
+  pub fn process_sample(&mut self, x: Sample) -> Sample {
+    if self.freq.next() {
+      self.update_coefficients();
+    }
+
+    let y = self.b0 * x + self.z1;
+
+    self.z1 = self.b1 * x - self.a1 * y + self.z2;
+    self.z2 = self.b2 * x - self.a2 * y;
+
+    y
+  }
+
+  /// Filter a block of samples in place.
+  pub fn process(&mut self, buffer: &mut [Sample]) {
+    for sample in buffer {
+      *sample = self.process_sample(*sample);
+    }
+  }
+
+  /// Filter a block of samples in place, with a frequency per sample.
+  ///
+  /// The frequencies aren’t smoothed, so that fast envelopes and audio-rate modulations keep their
+  /// shape. `freqs` must be as long as `buffer`.
+  pub fn process_modulated(&mut self, buffer: &mut [Sample], freqs: &[Hertz]) {
+    assert_eq!(buffer.len(), freqs.len());
+
+    for (sample, &freq) in buffer.iter_mut().zip(freqs) {
+      self.freq.set(clamp_cutoff(freq, self.rate));
+      self.update_coefficients();
+      *sample = self.process_sample(*sample);
+    }
+  }
+
+  fn update_coefficients(&mut self) {
+    let freq = clamp_cutoff(self.freq.current * self.freq_factor, self.rate);
+    let q = (self.q + self.q_offset).max(0.01);
+    let w0 = 2. * PI * freq / self.rate.0 as f32;
+    let (sin_w0, cos_w0) = unsafe { (sinf32(w0), cosf32(w0)) };
+    let alpha = sin_w0 / (2. * q);
+
+    let (b0, b1, b2, a0, a1, a2) = match self.kind {
+      BiquadKind::LowPass => {
+        let b1 = 1. - cos_w0;
+        (b1 * 0.5, b1, b1 * 0.5, 1. + alpha, -2. * cos_w0, 1. - alpha)
+      }
+
+      BiquadKind::HighPass => {
+        let b1 = -(1. + cos_w0);
+        (-b1 * 0.5, b1, -b1 * 0.5, 1. + alpha, -2. * cos_w0, 1. - alpha)
+      }
+
+      BiquadKind::BandPass => {
+        // constant 0 dB peak gain
+        (alpha, 0., -alpha, 1. + alpha, -2. * cos_w0, 1. - alpha)
+      }
+
+      BiquadKind::Peaking { gain } => {
+        let a = db_amplitude(gain);
+
+        (
+          1. + alpha * a,
+          -2. * cos_w0,
+          1. - alpha * a,
+          1. + alpha / a,
+          -2. * cos_w0,
+          1. - alpha / a
+        )
+      }
+
+      BiquadKind::LowShelf { gain } => {
+        let a = db_amplitude(gain);
+        let beta = 2. * unsafe { powf32(a, 0.5) } * alpha;
+
+        (
+          a * ((a + 1.) - (a - 1.) * cos_w0 + beta),
+          2. * a * ((a - 1.) - (a + 1.) * cos_w0),
+          a * ((a + 1.) - (a - 1.) * cos_w0 - beta),
+          (a + 1.) + (a - 1.) * cos_w0 + beta,
+          -2. * ((a - 1.) + (a + 1.) * cos_w0),
+          (a + 1.) + (a - 1.) * cos_w0 - beta
+        )
+      }
+
+      BiquadKind::HighShelf { gain } => {
+        let a = db_amplitude(gain);
+        let beta = 2. * unsafe { powf32(a, 0.5) } * alpha;
+
+        (
+          a * ((a + 1.) + (a - 1.) * cos_w0 + beta),
+          -2. * a * ((a - 1.) + (a + 1.) * cos_w0),
+          a * ((a + 1.) + (a - 1.) * cos_w0 - beta),
+          (a + 1.) - (a - 1.) * cos_w0 + beta,
+          2. * ((a - 1.) - (a + 1.) * cos_w0),
+          (a + 1.) - (a - 1.) * cos_w0 - beta
+        )
+      }
+    };
+
+    self.b0 = b0 / a0;
+    self.b1 = b1 / a0;
+    self.b2 = b2 / a0;
+    self.a1 = a1 / a0;
+    self.a2 = a2 / a0;
+  }
+}
+
+impl Modulable for Biquad {
+  /// The frequency is modulated in octaves; `q` is offset.
+  fn modulate(&mut self, param: &str, amount: f32) {
+    match param {
+      CUTOFF => self.freq_factor = unsafe { exp2f32(amount) },
+      RESONANCE => self.q_offset = amount,
+      _ => return
+    }
+
+    self.update_coefficients();
+  }
+}
+
+// Amplitude of a shelf or peak given its gain in decibels (A in the RBJ cookbook).
+fn db_amplitude(gain: f32) -> f32 {
+  unsafe { powf32(10., gain / 40.) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  // A block of decaying alternating samples, rich in high frequencies.
+  fn input() -> [Sample; 64] {
+    let mut input = [0.; 64];
+
+    for (i, x) in input.iter_mut().enumerate() {
+      *x = if i % 2 == 0 { 1. } else { -1. } / (1. + i as f32);
+    }
+
+    input
+  }
+
+  #[test]
+  fn state_variable_follows_modulated_cutoff_exactly() {
+    let mut modulated = StateVariable::new(SvfMode::LowPass, 10_000., 0.5, RATE);
+    let mut fixed = StateVariable::new(SvfMode::LowPass, 200., 0.5, RATE);
+    let mut a = input();
+    let mut b = input();
+
+    modulated.process_modulated(&mut a, &[200.; 64]);
+    fixed.process(&mut b);
+
+    assert_eq!(a, b);
+  }
+
+  #[test]
+  fn biquad_follows_modulated_frequency_exactly() {
+    let mut modulated = Biquad::low_pass(10_000., 0.707, RATE);
+    let mut fixed = Biquad::low_pass(200., 0.707, RATE);
+    let mut a = input();
+    let mut b = input();
+
+    modulated.process_modulated(&mut a, &[200.; 64]);
+    fixed.process(&mut b);
+
+    assert_eq!(a, b);
+  }
+
+  #[test]
+  fn cutoff_changes_are_smoothed() {
+    let mut filter = StateVariable::new(SvfMode::LowPass, 10_000., 0.5, RATE);
+    let mut fixed = StateVariable::new(SvfMode::LowPass, 200., 0.5, RATE);
+    let mut a = input();
+    let mut b = input();
+
+    filter.set_cutoff(200.);
+    filter.process(&mut a);
+    fixed.process(&mut b);
+
+    assert_ne!(a, b);
+    assert_eq!(filter.cutoff(), 200.);
+  }
+}
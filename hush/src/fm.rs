@@ -0,0 +1,502 @@
+//! Frequency modulation (FM) synthesis.
+//!
+//! FM synthesis – actually *phase* modulation, as on most FM chips – builds rich timbres out of
+//! nothing but sine waves. Each voice has four *operators*: sine oscillators with their own
+//! frequency ratio, level and envelope. Operators are wired together by an `Algorithm`: *modulators*
+//! shift the phase of the operators they feed, while *carriers* are heard.
+//!
+//! Phases follow the normalized-period convention of `hush::oscillator`: an operator’s output is
+//! added to the phase of the operators it modulates, in periods. A modulator at level 1 can thus
+//! shift its carrier’s phase by a full period (2π).
+
+use alloc::vec::Vec;
+use core::intrinsics::exp2f32;
+
+use envelope::{ADSR, Envelope};
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use note::Note;
+use oscillator::{Oscillator, Wave, sine_wave, wrap_phase};
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices};
+
+/// Number of operators of an FM voice.
+pub const OPERATORS: usize = 4;
+
+/// An operator of a `FmPatch`.
+#[derive(Clone, Debug)]
+pub struct Operator {
+  /// Frequency of the operator relative to the note’s frequency.
+  pub ratio: f32,
+  /// Output level: the amplitude of carriers, the modulation depth (in periods) of modulators.
+  pub level: f32,
+  /// Envelope of the operator’s level.
+  pub envelope: ADSR
+}
+
+impl Operator {
+  pub fn new(ratio: f32, level: f32, envelope: ADSR) -> Self {
+    Operator { ratio, level, envelope }
+  }
+}
+
+/// How operators are wired together.
+///
+/// Operators are numbered from 1 to 4; `a → b` means that `a` modulates `b` and `a + b` that both
+/// outputs are summed. Operator 1 is the one with feedback.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+  /// `1 → 2 → 3 → 4`; 4 is heard.
+  Stack,
+  /// `(1 + 2) → 3 → 4`; 4 is heard.
+  ParallelModulators,
+  /// `(1 + (2 → 3)) → 4`; 4 is heard.
+  BranchModulator,
+  /// `((1 → 2) + 3) → 4`; 4 is heard.
+  BranchStack,
+  /// `1 → 2` and `3 → 4`; 2 and 4 are heard.
+  TwoStacks,
+  /// `1 → 2`, `1 → 3` and `1 → 4`; 2, 3 and 4 are heard.
+  SharedModulator,
+  /// `1 → 2`, 3 and 4; 2, 3 and 4 are heard.
+  StackAndSines,
+  /// 1, 2, 3 and 4 are all heard, unmodulated.
+  Additive
+}
+
+impl Algorithm {
+  /// Operators (0-based) modulating a given operator (0-based).
+  ///
+  /// Modulators always come before the operator they modulate.
+  pub fn modulators(self, op: usize) -> &'static [usize] {
+    match (self, op) {
+      (Algorithm::Stack, 1) => &[0],
+      (Algorithm::Stack, 2) => &[1],
+      (Algorithm::Stack, 3) => &[2],
+
+      (Algorithm::ParallelModulators, 2) => &[0, 1],
+      (Algorithm::ParallelModulators, 3) => &[2],
+
+      (Algorithm::BranchModulator, 2) => &[1],
+      (Algorithm::BranchModulator, 3) => &[0, 2],
+
+      (Algorithm::BranchStack, 1) => &[0],
+      (Algorithm::BranchStack, 3) => &[1, 2],
+
+      (Algorithm::TwoStacks, 1) => &[0],
+      (Algorithm::TwoStacks, 3) => &[2],
+
+      (Algorithm::SharedModulator, 1) => &[0],
+      (Algorithm::SharedModulator, 2) => &[0],
+      (Algorithm::SharedModulator, 3) => &[0],
+
+      (Algorithm::StackAndSines, 1) => &[0],
+
+      _ => &[]
+    }
+  }
+
+  /// Operators (0-based) that are heard.
+  pub fn carriers(self) -> &'static [usize] {
+    match self {
+      Algorithm::Stack
+      | Algorithm::ParallelModulators
+      | Algorithm::BranchModulator
+      | Algorithm::BranchStack => &[3],
+      Algorithm::TwoStacks => &[1, 3],
+      Algorithm::SharedModulator | Algorithm::StackAndSines => &[1, 2, 3],
+      Algorithm::Additive => &[0, 1, 2, 3]
+    }
+  }
+}
+
+/// Settings of an FM voice.
+#[derive(Clone, Debug)]
+pub struct FmPatch {
+  pub operators: [Operator; OPERATORS],
+  pub algorithm: Algorithm,
+  /// Amount of operator 1’s output fed back into its own phase, in periods.
+  pub feedback: f32,
+  /// Output gain.
+  pub gain: f32
+}
+
+impl FmPatch {
+  pub fn new(operators: [Operator; OPERATORS], algorithm: Algorithm, feedback: f32, gain: f32) -> Self {
+    FmPatch { operators, algorithm, feedback, gain }
+  }
+
+  /// A plucked, growling bass.
+  pub fn bass(rate: SampleRate) -> Self {
+    Self::new(
+      [
+        Operator::new(1., 0.3, ADSR::valid(0.001, 0.3, 0.1, 0.1, rate)),
+        Operator::new(1., 0.4, ADSR::valid(0.001, 0.2, 0.2, 0.1, rate)),
+        Operator::new(2., 0.2, ADSR::valid(0.001, 0.15, 0., 0.1, rate)),
+        Operator::new(1., 1., ADSR::valid(0.001, 0.4, 0.7, 0.1, rate))
+      ],
+      Algorithm::Stack,
+      0.2,
+      0.8
+    )
+  }
+
+  /// A metallic bell, with inharmonic modulators.
+  pub fn bell(rate: SampleRate) -> Self {
+    Self::new(
+      [
+        Operator::new(3.5, 0.6, ADSR::valid(0.001, 2., 0., 2., rate)),
+        Operator::new(1., 0.8, ADSR::valid(0.001, 3., 0., 3., rate)),
+        Operator::new(1.41, 0.4, ADSR::valid(0.001, 1.5, 0., 1.5, rate)),
+        Operator::new(2., 0.5, ADSR::valid(0.001, 2.5, 0., 2.5, rate))
+      ],
+      Algorithm::TwoStacks,
+      0.,
+      0.6
+    )
+  }
+
+  /// A soft electric piano.
+  pub fn electric_piano(rate: SampleRate) -> Self {
+    Self::new(
+      [
+        Operator::new(14., 0.05, ADSR::valid(0.001, 0.2, 0., 0.2, rate)),
+        Operator::new(1., 0.8, ADSR::valid(0.002, 1.5, 0.3, 0.4, rate)),
+        Operator::new(1., 0.25, ADSR::valid(0.001, 1., 0.1, 0.4, rate)),
+        Operator::new(1., 0.8, ADSR::valid(0.002, 2., 0.4, 0.4, rate))
+      ],
+      Algorithm::TwoStacks,
+      0.,
+      0.6
+    )
+  }
+}
+
+/// A polyphonic FM synth.
+///
+/// Each note is played on its own voice, with its own operators, as described by a `FmPatch`.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Notes are scaled by their velocity and panned by their expression. Their timbre scales the
+/// output of the modulators – and hence the brightness – by up to a factor of 2 either way.
+pub struct Fm {
+  patch: FmPatch,
+  rate: SampleRate,
+  voices: Voices<FmVoice>
+}
+
+impl Fm {
+  pub fn new(patch: FmPatch, rate: SampleRate) -> Self {
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |_| FmVoice::new(&patch, rate));
+
+    Fm {
+      patch,
+      rate,
+      voices
+    }
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Current patch.
+  pub fn patch(&self) -> &FmPatch {
+    &self.patch
+  }
+
+  /// Change the patch.
+  ///
+  /// All currently playing notes are cut.
+  pub fn set_patch(&mut self, patch: FmPatch) {
+    let count = self.voices.len();
+
+    self.patch = patch;
+    self.voices = self.make_voices(count);
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<FmVoice> {
+    Voices::new(count, self.voices.stealing(), |_| FmVoice::new(&self.patch, self.rate))
+  }
+}
+
+impl Modulable for Fm {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.voices.modulate(param, amount);
+  }
+}
+
+impl Instrument for Fm {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+// A sine wave whose phase can be shifted, in periods.
+struct PhaseModulatedSine {
+  shift: f32
+}
+
+impl Wave for PhaseModulatedSine {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, _: Hertz) -> Sample {
+    sine_wave(wrap_phase(t + self.shift))
+  }
+}
+
+// An operator of a voice.
+struct VoiceOperator {
+  oscillator: Oscillator<PhaseModulatedSine>,
+  ratio: f32,
+  level: f32,
+  envelope: ADSR,
+  // envelope of the block being rendered
+  levels: Vec<f32>
+}
+
+/// A voice of a `Fm` synth.
+pub struct FmVoice {
+  operators: Vec<VoiceOperator>,
+  algorithm: Algorithm,
+  feedback: f32,
+  gain: f32,
+  freq: Hertz,
+  velocity: f32,
+  // factor of the modulators’ outputs
+  brightness: f32,
+  // last two outputs of operator 1, averaged for feedback
+  feedback_history: [f32; 2],
+  // modulations
+  pitch_factor: f32,
+  gain_factor: f32,
+  gate: bool,
+  level: f32,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl FmVoice {
+  fn new(patch: &FmPatch, rate: SampleRate) -> Self {
+    let operators = patch.operators.iter().map(|op| VoiceOperator {
+      oscillator: Oscillator::new(PhaseModulatedSine { shift: 0. }, rate),
+      ratio: op.ratio,
+      level: op.level,
+      envelope: op.envelope.clone(),
+      levels: Vec::new()
+    }).collect();
+
+    FmVoice {
+      operators,
+      algorithm: patch.algorithm,
+      feedback: patch.feedback,
+      gain: patch.gain,
+      freq: 0.,
+      velocity: 1.,
+      brightness: 1.,
+      feedback_history: [0.; 2],
+      pitch_factor: 1.,
+      gain_factor: 1.,
+      gate: false,
+      level: 0.,
+      pan: (1., 1.)
+    }
+  }
+
+  // Current level of the carriers’ envelopes.
+  fn carrier_level(&self, t: SampleTime) -> f32 {
+    self.algorithm.carriers().iter().map(|&i| self.operators[i].envelope.get(t)).fold(0., f32::max)
+  }
+}
+
+impl Voice for FmVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.freq = note.frequency();
+    self.velocity = expression.velocity;
+    self.brightness = unsafe { exp2f32(expression.timbre.unwrap_or(0.)) };
+    self.pan = expression.pan_gains();
+    self.gate = true;
+    self.feedback_history = [0.; 2];
+
+    for op in &mut self.operators {
+      op.oscillator.reset();
+      op.envelope.on(t);
+    }
+
+    self.level = self.carrier_level(t);
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+
+    for op in &mut self.operators {
+      op.envelope.off(t);
+    }
+  }
+
+  fn is_active(&self) -> bool {
+    self.gate || self.level > 0.
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let len = out.len();
+    let end = SampleTime(start.0 + len);
+    let freq = self.freq * self.pitch_factor;
+    let gain = self.gain * self.gain_factor * self.velocity;
+    let carriers = self.algorithm.carriers();
+
+    for op in &mut self.operators {
+      op.levels.resize(len, 0.);
+      op.envelope.render(start, &mut op.levels);
+    }
+
+    for (i, sample) in out.iter_mut().enumerate() {
+      let mut outputs = [0.; OPERATORS];
+
+      for k in 0..OPERATORS {
+        let shift = if k == 0 {
+          self.feedback * (self.feedback_history[0] + self.feedback_history[1]) * 0.5
+        } else {
+          self.algorithm.modulators(k).iter().map(|&m| outputs[m]).sum::<f32>() * self.brightness
+        };
+
+        let op = &mut self.operators[k];
+        op.oscillator.wave_mut().shift = shift;
+        outputs[k] = op.oscillator.next_sample(freq * op.ratio) * op.level * op.levels[i];
+      }
+
+      self.feedback_history = [self.feedback_history[1], outputs[0]];
+
+      let signal: f32 = carriers.iter().map(|&c| outputs[c]).sum();
+      *sample += signal * gain;
+    }
+
+    self.level = self.carrier_level(end);
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain_factor = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::f32::consts::PI;
+  use note::A4;
+
+  const RATE: SampleRate = SampleRate(48000);
+  const ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::Stack,
+    Algorithm::ParallelModulators,
+    Algorithm::BranchModulator,
+    Algorithm::BranchStack,
+    Algorithm::TwoStacks,
+    Algorithm::SharedModulator,
+    Algorithm::StackAndSines,
+    Algorithm::Additive
+  ];
+
+  // Render A4 on a voice whose operators have the given ratios and levels, and envelopes reaching
+  // their full level after two samples.
+  fn render(algorithm: Algorithm, operators: [(f32, f32); OPERATORS], feedback: f32) -> Vec<Sample> {
+    let envelope = ADSR::valid(1. / 48000., 1. / 48000., 1., 0.01, RATE);
+    let op = |(ratio, level)| Operator::new(ratio, level, envelope.clone());
+    let patch = FmPatch::new([op(operators[0]), op(operators[1]), op(operators[2]), op(operators[3])], algorithm, feedback, 1.);
+    let mut voice = FmVoice::new(&patch, RATE);
+    let mut out = [0.; 1000].to_vec();
+
+    voice.start(A4, Expression::default(), SampleTime(0));
+    voice.render(SampleTime(0), &mut out);
+    out
+  }
+
+  // Phase of a partial of A4 at a given sample, in periods.
+  fn phase(ratio: f32, i: usize) -> f32 {
+    ratio * 440. * i as f32 / 48000.
+  }
+
+  fn assert_close(signal: &[Sample], expected: impl Fn(usize) -> Sample) {
+    for (i, &sample) in signal.iter().enumerate().skip(2) {
+      assert!((sample - expected(i)).abs() < 1e-3, "sample {}: {} instead of {}", i, sample, expected(i));
+    }
+  }
+
+  #[test]
+  fn silent_modulators_give_carrier_sines() {
+    for &algorithm in &ALGORITHMS {
+      let carriers = algorithm.carriers();
+      let mut operators = [(1., 0.), (2., 0.), (3., 0.), (4., 0.)];
+
+      for &c in carriers {
+        operators[c].1 = 1.;
+      }
+
+      let signal = render(algorithm, operators, 0.);
+
+      assert_close(&signal, |i| carriers.iter().map(|&c| (2. * PI * phase(operators[c].0, i)).sin()).sum());
+    }
+  }
+
+  #[test]
+  fn no_feedback_gives_pure_sine() {
+    let operators = [(1., 1.), (1., 0.), (1., 0.), (1., 0.)];
+
+    assert_close(&render(Algorithm::Additive, operators, 0.), |i| (2. * PI * phase(1., i)).sin());
+
+    let fed_back = render(Algorithm::Additive, operators, 0.5);
+    assert!(fed_back.iter().enumerate().skip(2).any(|(i, &sample)| (sample - (2. * PI * phase(1., i)).sin()).abs() > 0.1));
+  }
+
+  #[test]
+  fn modulation_shifts_phases_in_periods() {
+    // operator 3, at twice the note’s frequency, shifts the phase of operator 4 by up to a quarter
+    // period
+    let operators = [(1., 0.), (1., 0.), (2., 0.25), (3., 1.)];
+
+    assert_close(&render(Algorithm::Stack, operators, 0.), |i| {
+      let shift = 0.25 * (2. * PI * phase(2., i)).sin();
+      (2. * PI * (phase(3., i) + shift)).sin()
+    });
+  }
+}
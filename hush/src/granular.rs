@@ -0,0 +1,510 @@
+//! Granular synthesis.
+//!
+//! Granular synthesis chops a *source* – a recording or the rendered output of an oscillator – into
+//! many short, overlapping *grains*, each faded in and out by a window. Scattering grains around a
+//! position in the source gives clouds, textures and frozen, time-stretched sounds.
+//!
+//! Grains are scattered with a seeded pseudo-random generator, reseeded whenever a voice starts a
+//! note: the same seed and the same notes always give the same grains.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use core::intrinsics::{cosf32, expf32, floorf32, sqrtf32};
+
+use envelope::{ADSR, Envelope};
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use note::Note;
+use oscillator::{Oscillator, Wave};
+use random::Random;
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices, voice_seed};
+
+/// Maximum number of grains a voice plays at once; grains due while that many are playing are
+/// skipped.
+pub const MAX_GRAINS: usize = 64;
+
+/// Default seed of the grain scattering.
+pub const DEFAULT_SEED: u32 = 0x5ee1_90a3;
+
+/// Shape of the fade in and out of grains.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Window {
+  /// No fade: harsh and clicky.
+  Rectangle,
+  /// Linear fade in and out.
+  Triangle,
+  /// Raised cosine: smooth, the usual choice.
+  Hann,
+  /// Gaussian bell: smooth, with a narrower peak than `Hann`.
+  Gaussian
+}
+
+impl Window {
+  /// Value of the window at `x` in `[0; 1]`, the progress through a grain.
+  pub fn get(&self, x: f32) -> f32 {
+    match *self {
+      Window::Rectangle => 1.,
+      Window::Triangle => 1. - (2. * x - 1.).max(1. - 2. * x),
+      Window::Hann => 0.5 - 0.5 * unsafe { cosf32(2. * PI * x) },
+      Window::Gaussian => {
+        // standard deviation of a sixth of the grain
+        let d = (x - 0.5) * 6.;
+        unsafe { expf32(-0.5 * d * d) }
+      }
+    }
+  }
+}
+
+// Parameters shared by all voices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Settings {
+  size: Time,
+  density: f32,
+  position: f32,
+  jitter: f32,
+  window: Window
+}
+
+/// A polyphonic granular synth.
+///
+/// Each note plays a stream of grains from the source at the pitch of the note, relative to the root
+/// note of the source. The stream is set by:
+///
+///   - The grain *size*: how long each grain lasts.
+///   - The *density*: how many grains start per second. Grains overlap when the density times the
+///     size exceeds 1.
+///   - The *position* in the source, in `[0; 1]`, grains start at.
+///   - The position *jitter*, in `[0; 1]`: how far, relative to the length of the source, the start
+///     of each grain is scattered around the position.
+///   - The *window* shape of the grains.
+///
+/// The source is read as a loop. Changing the settings affects the grains started afterwards, so it
+/// can be done while notes are playing.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Notes are scaled by their velocity and panned by their expression.
+pub struct Granular {
+  source: Rc<Vec<Sample>>,
+  root: Hertz,
+  settings: Settings,
+  envelope: ADSR,
+  seed: u32,
+  rate: SampleRate,
+  voices: Voices<GranularVoice>
+}
+
+impl Granular {
+  /// Create a granular synth playing grains of `source` – recorded at the output sample rate
+  /// `rate` – at their original speed when playing `root`.
+  ///
+  /// By default, 50 ms Hann grains are started 40 times per second from the middle of the source,
+  /// jittered by 5%.
+  pub fn new(source: Vec<Sample>, root: Note, rate: SampleRate) -> Self {
+    let settings = Settings {
+      size: 0.05,
+      density: 40.,
+      position: 0.5,
+      jitter: 0.05,
+      window: Window::Hann
+    };
+
+    let source = Rc::new(source);
+    let envelope = ADSR::valid(0.05, 0.001, 1., 0.3, rate);
+    let root = root.frequency();
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |i| {
+      GranularVoice::new(source.clone(), root, settings, &envelope, voice_seed(DEFAULT_SEED, i), rate)
+    });
+
+    Granular {
+      source,
+      root,
+      settings,
+      envelope,
+      seed: DEFAULT_SEED,
+      rate,
+      voices
+    }
+  }
+
+  /// Create a granular synth whose source is `duration` seconds of `wave` played at the frequency
+  /// of `root`.
+  pub fn from_wave<W>(wave: W, root: Note, duration: Time, rate: SampleRate) -> Self where W: Wave {
+    let mut oscillator = Oscillator::new(wave, rate);
+    let source = oscillator.sample(SampleTime(0), SampleTime::from_time(duration, rate), root.frequency()).to_vec();
+
+    Self::new(source, root, rate)
+  }
+
+  /// Change the grain size (50 ms by default).
+  pub fn with_grain_size(mut self, size: Time) -> Self {
+    self.set_grain_size(size);
+    self
+  }
+
+  /// Change the density, in grains per second (40 by default).
+  pub fn with_density(mut self, density: f32) -> Self {
+    self.set_density(density);
+    self
+  }
+
+  /// Change the position in the source (0.5 by default).
+  pub fn with_position(mut self, position: f32) -> Self {
+    self.set_position(position);
+    self
+  }
+
+  /// Change the position jitter (0.05 by default).
+  pub fn with_jitter(mut self, jitter: f32) -> Self {
+    self.set_jitter(jitter);
+    self
+  }
+
+  /// Change the window shape (`Window::Hann` by default).
+  pub fn with_window(mut self, window: Window) -> Self {
+    self.set_window(window);
+    self
+  }
+
+  /// Change the envelope of the grain stream.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_envelope(mut self, envelope: ADSR) -> Self {
+    let count = self.voices.len();
+
+    self.envelope = envelope;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the seed of the grain scattering.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_seed(mut self, seed: u32) -> Self {
+    let count = self.voices.len();
+
+    self.seed = seed;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Samples grains are taken from.
+  pub fn source(&self) -> &[Sample] {
+    &self.source
+  }
+
+  pub fn grain_size(&self) -> Time {
+    self.settings.size
+  }
+
+  pub fn density(&self) -> f32 {
+    self.settings.density
+  }
+
+  pub fn position(&self) -> f32 {
+    self.settings.position
+  }
+
+  pub fn jitter(&self) -> f32 {
+    self.settings.jitter
+  }
+
+  pub fn window(&self) -> Window {
+    self.settings.window
+  }
+
+  /// Change the grain size of playing and future notes.
+  pub fn set_grain_size(&mut self, size: Time) {
+    self.settings.size = size.max(0.);
+    self.update_settings();
+  }
+
+  /// Change the density of playing and future notes.
+  pub fn set_density(&mut self, density: f32) {
+    self.settings.density = density.max(0.);
+    self.update_settings();
+  }
+
+  /// Change the position of playing and future notes.
+  pub fn set_position(&mut self, position: f32) {
+    self.settings.position = position.max(0.).min(1.);
+    self.update_settings();
+  }
+
+  /// Change the position jitter of playing and future notes.
+  pub fn set_jitter(&mut self, jitter: f32) {
+    self.settings.jitter = jitter.max(0.).min(1.);
+    self.update_settings();
+  }
+
+  /// Change the window shape of playing and future notes.
+  pub fn set_window(&mut self, window: Window) {
+    self.settings.window = window;
+    self.update_settings();
+  }
+
+  fn update_settings(&mut self) {
+    let settings = self.settings;
+
+    for voice in self.voices.iter_mut() {
+      voice.settings = settings;
+    }
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<GranularVoice> {
+    Voices::new(count, self.voices.stealing(), |i| {
+      GranularVoice::new(self.source.clone(), self.root, self.settings, &self.envelope, voice_seed(self.seed, i), self.rate)
+    })
+  }
+}
+
+impl Modulable for Granular {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.voices.modulate(param, amount);
+  }
+}
+
+impl Instrument for Granular {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+// A grain being played.
+#[derive(Clone, Copy, Debug)]
+struct Grain {
+  // read position in the source, in samples
+  position: f32,
+  // age and length, in samples
+  age: f32,
+  length: f32
+}
+
+/// A voice of a `Granular` synth: a stream of grains.
+pub struct GranularVoice {
+  source: Rc<Vec<Sample>>,
+  root: Hertz,
+  settings: Settings,
+  envelope: ADSR,
+  // seed the generator is reset to when a note starts
+  seed: u32,
+  random: Random,
+  samples_per_sec: f32,
+  grains: Vec<Grain>,
+  // samples before the next grain starts
+  until_next: f32,
+  // playback speed of grains
+  speed: f32,
+  velocity: f32,
+  gate: bool,
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  level: f32,
+  buffer: Vec<Sample>,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl GranularVoice {
+  fn new(source: Rc<Vec<Sample>>, root: Hertz, settings: Settings, envelope: &ADSR, seed: u32, rate: SampleRate) -> Self {
+    GranularVoice {
+      source,
+      root,
+      settings,
+      envelope: envelope.clone(),
+      seed,
+      random: Random::new(seed),
+      samples_per_sec: rate.0 as f32,
+      grains: Vec::with_capacity(MAX_GRAINS),
+      until_next: 0.,
+      speed: 1.,
+      velocity: 1.,
+      gate: false,
+      pitch_factor: 1.,
+      gain: 1.,
+      level: 0.,
+      buffer: Vec::new(),
+      pan: (1., 1.)
+    }
+  }
+
+  fn spawn_grain(&mut self) {
+    let len = self.source.len() as f32;
+    let length = self.settings.size * self.samples_per_sec;
+
+    if self.grains.len() >= MAX_GRAINS || length < 1. || len == 0. {
+      return;
+    }
+
+    let position = (self.settings.position + self.settings.jitter * self.random.next_bipolar()) * len;
+
+    self.grains.push(Grain {
+      position: wrap(position, len),
+      age: 0.,
+      length
+    });
+  }
+}
+
+// Wrap a position into `[0; len[`.
+#[inline(always)]
+fn wrap(position: f32, len: f32) -> f32 {
+  position - unsafe { floorf32(position / len) } * len
+}
+
+impl Voice for GranularVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.velocity = expression.velocity;
+    self.pan = expression.pan_gains();
+    self.speed = note.frequency() / self.root;
+    self.grains.clear();
+    self.random = Random::new(self.seed);
+    self.until_next = 0.;
+    self.gate = true;
+    self.envelope.on(t);
+    self.level = self.envelope.get(t);
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+    self.envelope.off(t);
+  }
+
+  fn is_active(&self) -> bool {
+    self.gate || self.level > 0.
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let end = SampleTime(start.0 + out.len());
+    let speed = self.speed * self.pitch_factor;
+    let len = self.source.len();
+    let period = if self.settings.density > 0. { self.samples_per_sec / self.settings.density } else { 0. };
+
+    // overlapping grains are uncorrelated: normalize their power
+    let overlap = self.settings.density * self.settings.size;
+    let normalization = 1. / unsafe { sqrtf32(overlap.max(1.)) };
+
+    self.buffer.clear();
+    self.buffer.resize(out.len(), 0.);
+
+    for i in 0 .. out.len() {
+      if period > 0. {
+        if self.until_next <= 0. {
+          self.spawn_grain();
+          self.until_next += period;
+        }
+
+        self.until_next -= 1.;
+      }
+
+      let window = self.settings.window;
+      let source = &self.source;
+      let mut signal = 0.;
+
+      for grain in &mut self.grains {
+        // linear interpolation, reading the source as a loop
+        let index = unsafe { floorf32(grain.position) };
+        let fraction = grain.position - index;
+        let a = source[index as usize % len];
+        let b = source[(index as usize + 1) % len];
+
+        signal += (a + (b - a) * fraction) * window.get(grain.age / grain.length);
+
+        grain.position = wrap(grain.position + speed, len as f32);
+        grain.age += 1.;
+      }
+
+      self.grains.retain(|grain| grain.age < grain.length);
+      self.buffer[i] = signal * normalization;
+    }
+
+    self.envelope.apply(start, &mut self.buffer);
+
+    let gain = self.gain * self.velocity;
+
+    for (sample, signal) in out.iter_mut().zip(&self.buffer) {
+      *sample += signal * gain;
+    }
+
+    self.level = self.envelope.get(end);
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::{A4, C4};
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  fn render(granular: &mut Granular, start: usize, len: usize) -> Vec<Sample> {
+    granular.get_samples(SampleTime(start), SampleTime(start + len)).to_vec()
+  }
+
+  #[test]
+  fn same_notes_give_same_grains() {
+    let source = (0..4410).map(|i| (i as f32 * 0.1).sin()).collect();
+    let mut granular = Granular::new(source, A4, RATE).with_jitter(0.5).with_voices(1);
+
+    granular.note_on(C4, NoteChannel::default());
+    let first = render(&mut granular, 0, 4410);
+    granular.note_off(NoteChannel::default());
+    render(&mut granular, 4410, 44100);
+
+    granular.note_on(C4, NoteChannel::default());
+    let second = render(&mut granular, 48510, 4410);
+
+    assert_eq!(first, second);
+  }
+}
@@ -1,10 +1,22 @@
 //! Instruments.
 
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use envelope::{ADSR, Envelope};
 use hertz::Hertz;
+use modulation::{DUTY, MORPH, Modulable};
+use noise::Noise;
 use note::Note;
-use oscillator::{Oscillator, sine_wave, square_wave, triangle_wave, sawtooth_wave};
-use time::{SampleTime, Time};
+use oscillator::{Pulse, Wave, Waveform};
+use time::{SampleRate, SampleTime, Time};
 use sample::Sample;
+use unison::{Unison, UnisonOscillator};
+use voice::{Voice, VoiceStealing, Voices, voice_seed};
+use wavetable::{Wavetable, WavetableWave};
+
+pub use drum::{Drum, DrumMachine};
 
 /// An instrument.
 ///
@@ -12,7 +24,14 @@ use sample::Sample;
 /// from each other, allowing for a rich and mixed audio signal. This is done through “note channels”.
 pub trait Instrument {
   /// Trigger a note at a given time on a given note channel.
-  fn note_on(&mut self, note: Note, channel: NoteChannel);
+  ///
+  /// The note is played with the default `Expression`: full velocity and no other expression.
+  fn note_on(&mut self, note: Note, channel: NoteChannel) {
+    self.note_on_with(note, channel, Expression::default());
+  }
+
+  /// Trigger a note on a given note channel, with an expression.
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression);
 
   /// Release a note.
   fn note_off(&mut self, note_channel: NoteChannel);
@@ -22,6 +41,21 @@ pub trait Instrument {
 
   /// Get a few samples from this instrument.
   fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample];
+
+  /// Get a few stereo samples from this instrument, into `left` and `right`.
+  ///
+  /// Both slices are expected to be `end - start` samples long. By default, the mono samples of
+  /// `Instrument::get_samples` are played on both sides.
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    let samples = self.get_samples(start, end);
+
+    for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+      let sample = samples.get(i).cloned().unwrap_or(0.);
+
+      *l = sample;
+      *r = sample;
+    }
+  }
 }
 
 /// A note channel.
@@ -41,75 +75,769 @@ impl NoteChannel {
   }
 }
 
-/// A note pressed at a given time.
-pub struct PressedNote {
-  note: Note,
-  channel: NoteChannel,
+/// Expression of a note: how it’s played.
+///
+/// The velocity – how hard the note is played – is always given, in `[0; 1]`. The other dimensions
+/// are optional; instruments use their own settings for the ones that aren’t given:
+///
+///   - The *pressure*, in `[0; 1]`: how hard a note is held, as with aftertouch.
+///   - The *timbre*, in `[-1; 1]`: how bright the note sounds, 0 being the instrument’s own timbre.
+///   - The *pan*, in `[-1; 1]`: from left to right, 0 being the center.
+///
+/// What each dimension does depends on the instrument – amplitude, filter cutoff, brightness, etc.;
+/// see their documentation. All instruments scale their amplitude by the velocity, and all
+/// instruments rendering stereo samples honor the pan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expression {
+  pub velocity: f32,
+  pub pressure: Option<f32>,
+  pub timbre: Option<f32>,
+  pub pan: Option<f32>
 }
 
-/// A synth.
-pub struct Synth {
-  pressed: Option<PressedNote>,
-  oscillator: Oscillator<fn(Hertz) -> Sample>
+impl Expression {
+  pub fn new(velocity: f32) -> Self {
+    Expression {
+      velocity,
+      pressure: None,
+      timbre: None,
+      pan: None
+    }
+  }
+
+  pub fn with_pressure(self, pressure: f32) -> Self {
+    Expression { pressure: Some(pressure), ..self }
+  }
+
+  pub fn with_timbre(self, timbre: f32) -> Self {
+    Expression { timbre: Some(timbre), ..self }
+  }
+
+  pub fn with_pan(self, pan: f32) -> Self {
+    Expression { pan: Some(pan), ..self }
+  }
+
+  /// Gains of the left and right sides for the pan.
+  ///
+  /// Centered notes – or notes without pan – play at full level on both sides; panning attenuates
+  /// the opposite side.
+  pub fn pan_gains(&self) -> (f32, f32) {
+    let pan = self.pan.unwrap_or(0.).max(-1.).min(1.);
+    ((1. - pan).min(1.), (1. + pan).min(1.))
+  }
+}
+
+impl Default for Expression {
+  /// Full velocity, without any other expression.
+  fn default() -> Self {
+    Self::new(1.)
+  }
+}
+
+/// Default number of voices of a `Synth`.
+pub const DEFAULT_VOICES: usize = 8;
+
+/// A polyphonic synth.
+///
+/// Every note is played on its own voice, with its own oscillator phase and – if the synth has one
+/// – its own envelope. All active voices are mixed together. When more notes are pressed than there
+/// are voices, a voice is stolen according to the synth’s `VoiceStealing` policy.
+///
+/// Any `Envelope` can be used; the type parameter defaults to `ADSR`.
+///
+/// Each voice can play several detuned copies of its oscillator, spread across the stereo field
+/// (see `Synth::with_unison`); the stereo image is available through
+/// `Instrument::get_stereo_samples`.
+///
+/// Besides the usual waveforms, a synth can play pulse waves (see `Synth::pulse`), wavetables (see
+/// `Synth::wavetable`), noise (see `Synth::noise`) and any other wave – such as the combinators of
+/// `hush::combinator` – (see `Synth::from_wave`).
+///
+/// A synth can be modulated (see `Modulable`) on `modulation::PITCH`, `modulation::AMPLITUDE`, and
+/// – for pulse waves and wavetables – on `modulation::DUTY` and `modulation::MORPH`.
+///
+/// Notes are scaled by their velocity and panned by their expression.
+pub struct Synth<E = ADSR> {
+  source: Source,
+  envelope: Option<E>,
+  unison: Unison,
+  rate: SampleRate,
+  voices: Voices<SynthVoice<E>>
 }
 
 impl Synth {
-  pub fn sine() -> Self {
-    let oscillator = Oscillator::new(sine_wave as fn(_) -> _);
+  pub fn new(waveform: Waveform, rate: SampleRate) -> Self {
+    Self::from_source(Source::Waveform(waveform), rate)
+  }
+
+  /// A synth playing a band-limited pulse wave with a given duty cycle.
+  ///
+  /// The duty cycle can be modulated with `modulation::DUTY` for pulse-width modulation.
+  pub fn pulse(duty: f32, rate: SampleRate) -> Self {
+    Self::from_source(Source::Pulse(Pulse::new(duty)), rate)
+  }
+
+  /// A synth playing a wavetable at a given morph position.
+  ///
+  /// The morph position can be modulated with `modulation::MORPH`.
+  pub fn wavetable(table: Rc<Wavetable>, morph: f32, rate: SampleRate) -> Self {
+    Self::from_source(Source::Wavetable(WavetableWave::new(table, morph)), rate)
+  }
+
+  /// A synth playing any wave.
+  ///
+  /// Each voice plays its own copy of the wave.
+  pub fn from_wave<W>(wave: W, rate: SampleRate) -> Self where W: 'static + Wave + Clone {
+    Self::from_source(Source::Custom(Box::new(wave)), rate)
+  }
+
+  /// A synth playing noise.
+  ///
+  /// Each voice gets its own noise, seeded from the noise’s seed. The frequency of the notes only
+  /// matters to LFSR noises.
+  pub fn noise(noise: Noise, rate: SampleRate) -> Self {
+    Self::from_source(Source::Noise(noise), rate)
+  }
+
+  fn from_source(source: Source, rate: SampleRate) -> Self {
+    let unison = Unison::default();
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |i| SynthVoice::new(source.voice(i), None, unison.voice(i), rate));
 
     Synth {
-      pressed: None,
-      oscillator
+      source,
+      envelope: None,
+      unison,
+      rate,
+      voices
     }
   }
 
-  pub fn square() -> Self {
-    let oscillator = Oscillator::new(square_wave as fn(_) -> _);
+  pub fn sine(rate: SampleRate) -> Self {
+    Self::new(Waveform::Sine, rate)
+  }
+
+  pub fn square(rate: SampleRate) -> Self {
+    Self::new(Waveform::Square, rate)
+  }
+
+  pub fn triangle(rate: SampleRate) -> Self {
+    Self::new(Waveform::Triangle, rate)
+  }
+
+  pub fn sawtooth(rate: SampleRate) -> Self {
+    Self::new(Waveform::Sawtooth, rate)
+  }
+
+  /// Anti-aliased version of `Synth::square`.
+  pub fn square_bandlimited(rate: SampleRate) -> Self {
+    Self::new(Waveform::SquareBandLimited, rate)
+  }
+
+  /// Anti-aliased version of `Synth::triangle`.
+  pub fn triangle_bandlimited(rate: SampleRate) -> Self {
+    Self::new(Waveform::TriangleBandLimited, rate)
+  }
+
+  /// Anti-aliased version of `Synth::sawtooth`.
+  pub fn sawtooth_bandlimited(rate: SampleRate) -> Self {
+    Self::new(Waveform::SawtoothBandLimited, rate)
+  }
+}
+
+impl<E> Synth<E> where E: Envelope + Clone {
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Play several detuned copies of the oscillator on each voice.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_unison(mut self, unison: Unison) -> Self {
+    let count = self.voices.len();
+
+    self.unison = unison;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Give each voice a copy of an envelope.
+  ///
+  /// Without envelope, voices are simply switched on and off. All currently playing notes are cut.
+  pub fn with_envelope<F>(self, envelope: F) -> Synth<F> where F: Envelope + Clone {
+    let count = self.voices.len();
+    let stealing = self.voices.stealing();
+    let unison = self.unison;
+    let rate = self.rate;
+    let voices = Voices::new(count, stealing, |i| SynthVoice::new(self.source.voice(i), Some(envelope.clone()), unison.voice(i), rate));
 
     Synth {
-      pressed: None,
-      oscillator
+      source: self.source,
+      envelope: Some(envelope),
+      unison,
+      rate,
+      voices
     }
   }
 
-  pub fn triangle() -> Self {
-    let oscillator = Oscillator::new(triangle_wave as fn(_) -> _);
+  fn make_voices(&self, count: usize) -> Voices<SynthVoice<E>> {
+    Voices::new(count, self.voices.stealing(), |i| SynthVoice::new(self.source.voice(i), self.envelope.clone(), self.unison.voice(i), self.rate))
+  }
+}
 
-    Synth {
-      pressed: None,
-      oscillator
+// What a synth plays.
+#[derive(Clone)]
+enum Source {
+  Waveform(Waveform),
+  Pulse(Pulse),
+  Wavetable(WavetableWave),
+  Noise(Noise),
+  Custom(Box<dyn CustomWave>)
+}
+
+// A wave that can be cloned behind a box.
+trait CustomWave: Wave {
+  fn box_clone(&self) -> Box<dyn CustomWave>;
+}
+
+impl<W> CustomWave for W where W: 'static + Wave + Clone {
+  fn box_clone(&self) -> Box<dyn CustomWave> {
+    Box::new(self.clone())
+  }
+}
+
+impl Clone for Box<dyn CustomWave> {
+  fn clone(&self) -> Self {
+    self.box_clone()
+  }
+}
+
+impl Source {
+  // Make the source of the voice at `index`; noises are given different seeds.
+  fn voice(&self, index: usize) -> Source {
+    match *self {
+      Source::Noise(ref noise) => Source::Noise(Noise::with_seed(noise.color(), voice_seed(noise.seed(), index))),
+      ref source => source.clone()
     }
   }
+}
 
-  pub fn sawtooth() -> Self {
-    let oscillator = Oscillator::new(sawtooth_wave as fn(_) -> _);
+impl Wave for Source {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    match *self {
+      Source::Waveform(ref mut waveform) => waveform.sample(t, dt),
+      Source::Pulse(ref mut pulse) => pulse.sample(t, dt),
+      Source::Wavetable(ref mut wave) => wave.sample(t, dt),
+      Source::Custom(ref mut wave) => wave.sample(t, dt),
+      Source::Noise(ref mut noise) => noise.sample(t, dt)
+    }
+  }
 
-    Synth {
-      pressed: None,
-      oscillator
+  // only custom waves keep a state
+  fn reset(&mut self) {
+    if let Source::Custom(ref mut wave) = *self {
+      wave.reset();
     }
   }
 }
 
-impl Instrument for Synth {
-  fn note_on(&mut self, note: Note, channel: NoteChannel) {
-    self.pressed = Some(PressedNote { note, channel });
+impl<E> Modulable for Synth<E> where E: Envelope {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    match param {
+      DUTY => {
+        if let Source::Pulse(pulse) = self.source {
+          for voice in self.voices.iter_mut() {
+            for wave in voice.oscillator.waves_mut() {
+              if let Source::Pulse(ref mut voice_pulse) = *wave {
+                voice_pulse.duty = pulse.duty + amount;
+              }
+            }
+          }
+        }
+      }
+
+      MORPH => {
+        if let Source::Wavetable(ref wave) = self.source {
+          for voice in self.voices.iter_mut() {
+            for voice_wave in voice.oscillator.waves_mut() {
+              if let Source::Wavetable(ref mut voice_wave) = *voice_wave {
+                voice_wave.morph = wave.morph + amount;
+              }
+            }
+          }
+        }
+      }
+
+      _ => self.voices.modulate(param, amount)
+    }
+  }
+}
+
+impl<E> Instrument for Synth<E> where E: Envelope {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+/// A voice of a `Synth`.
+pub struct SynthVoice<E = ADSR> {
+  oscillator: UnisonOscillator<Source>,
+  envelope: Option<E>,
+  freq: Hertz,
+  velocity: f32,
+  // gains of the left and right sides
+  pan: (f32, f32),
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  gate: bool,
+  level: f32,
+  buffer: Vec<Sample>,
+  // right side of stereo renders, the left one being the buffer
+  right_buffer: Vec<Sample>,
+  // envelope of stereo renders
+  levels: Vec<f32>
+}
+
+impl<E> SynthVoice<E> where E: Envelope {
+  fn new(source: Source, envelope: Option<E>, unison: Unison, rate: SampleRate) -> Self {
+    SynthVoice {
+      oscillator: UnisonOscillator::new(source, unison, rate),
+      envelope,
+      freq: 0.,
+      velocity: 1.,
+      pan: (1., 1.),
+      pitch_factor: 1.,
+      gain: 1.,
+      gate: false,
+      level: 0.,
+      buffer: Vec::new(),
+      right_buffer: Vec::new(),
+      levels: Vec::new()
+    }
+  }
+}
+
+impl<E> Voice for SynthVoice<E> where E: Envelope {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.freq = note.frequency();
+    self.velocity = expression.velocity;
+    self.pan = expression.pan_gains();
+    self.gate = true;
+    self.oscillator.restart();
+
+    if let Some(ref mut envelope) = self.envelope {
+      envelope.on(t);
+      self.level = envelope.get(t);
+    } else {
+      self.level = 1.;
+    }
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+
+    match self.envelope {
+      Some(ref mut envelope) => envelope.off(t),
+      None => self.level = 0.
+    }
+  }
+
+  fn is_active(&self) -> bool {
+    self.gate || self.level > 0.
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let end = SampleTime(start.0 + out.len());
+
+    self.buffer.clear();
+
+    let freq = self.freq * self.pitch_factor;
+    let gain = self.gain * self.velocity;
+
+    for _ in 0..out.len() {
+      let signal = self.oscillator.next_sample(freq) * gain;
+      self.buffer.push(signal);
+    }
+
+    if let Some(ref mut envelope) = self.envelope {
+      envelope.apply(start, &mut self.buffer);
+      self.level = envelope.get(end);
+    }
+
+    for (sample, signal) in out.iter_mut().zip(&self.buffer) {
+      *sample += signal;
+    }
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
   }
 
-  fn note_off(&mut self, _: NoteChannel) {
-    self.pressed = None;
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+
+  fn render_stereo(&mut self, start: SampleTime, left: &mut [Sample], right: &mut [Sample], _: &mut Vec<Sample>) {
+    let len = left.len();
+    let end = SampleTime(start.0 + len);
+
+    self.buffer.clear();
+    self.right_buffer.clear();
+
+    let freq = self.freq * self.pitch_factor;
+    let gain = self.gain * self.velocity;
+    let (left_gain, right_gain) = self.pan;
+
+    for _ in 0..len {
+      let (l, r) = self.oscillator.next_stereo_sample(freq);
+
+      self.buffer.push(l * gain * left_gain);
+      self.right_buffer.push(r * gain * right_gain);
+    }
+
+    // the envelope is rendered once for both sides
+    if let Some(ref mut envelope) = self.envelope {
+      self.levels.resize(len, 0.);
+      envelope.render(start, &mut self.levels);
+      self.level = envelope.get(end);
+
+      for ((l, r), level) in self.buffer.iter_mut().zip(&mut self.right_buffer).zip(&self.levels) {
+        *l *= level;
+        *r *= level;
+      }
+    }
+
+    for ((sample, signal), (right_sample, right_signal)) in left.iter_mut().zip(&self.buffer).zip(right.iter_mut().zip(&self.right_buffer)) {
+      *sample += signal;
+      *right_sample += right_signal;
+    }
+  }
+}
+
+/// An instrument whose output is shaped by an envelope.
+///
+/// This is a paraphonic wrapper: a single envelope is shared by all the notes of the wrapped
+/// instrument. It’s switched on when a note is pressed while no other note is held, and switched
+/// off when the last held note is released. Notes pressed while others are held join the envelope
+/// where it is, without retriggering it.
+///
+/// The last released note keeps playing until the envelope’s tail is over, and only then is it
+/// released on the wrapped instrument. Notes released while others are still held are released
+/// right away, and so are the notes of the tail when a new note is pressed during it: the envelope
+/// is then switched on again according to its retrigger mode (see `Retrigger`); the default
+/// `Retrigger::Reset` restarts it from 0, while `Retrigger::Continue` carries on from its level.
+///
+/// For an envelope per note, prefer a polyphonic instrument with per-voice envelopes, such as
+/// `Synth::with_envelope`.
+pub struct Enveloped<I, E = ADSR> {
+  instrument: I,
+  envelope: E,
+  // channels currently held
+  held: Vec<NoteChannel>,
+  // channels released while the envelope plays its tail
+  releasing: Vec<NoteChannel>,
+  // sample time at which the next block starts; events without a time are applied at that time
+  now: SampleTime,
+  buffer: Vec<Sample>
+}
+
+impl<I, E> Enveloped<I, E> where I: Instrument, E: Envelope {
+  pub fn new(instrument: I, envelope: E) -> Self {
+    Enveloped {
+      instrument,
+      envelope,
+      held: Vec::new(),
+      releasing: Vec::new(),
+      now: SampleTime(0),
+      buffer: Vec::new()
+    }
+  }
+
+  /// Wrapped instrument.
+  pub fn instrument(&self) -> &I {
+    &self.instrument
+  }
+
+  /// Wrapped instrument.
+  pub fn instrument_mut(&mut self) -> &mut I {
+    &mut self.instrument
+  }
+
+  /// Envelope applied to the instrument.
+  pub fn envelope(&self) -> &E {
+    &self.envelope
+  }
+
+  /// Trigger a note on a given note channel, with an expression, switching the envelope on at
+  /// sample time `t` if no other note is held.
+  ///
+  /// `t` should be within the next block; `Instrument::note_on_with` uses the start of that block.
+  pub fn note_on_at(&mut self, note: Note, channel: NoteChannel, expression: Expression, t: SampleTime) {
+    self.releasing.retain(|&ch| ch != channel);
+    self.flush_releasing();
+
+    // notes joining held ones don’t retrigger the envelope
+    if self.held.is_empty() {
+      self.envelope.on(t);
+    }
+
+    if !self.held.contains(&channel) {
+      self.held.push(channel);
+    }
+
+    self.instrument.note_on_with(note, channel, expression);
+  }
+
+  /// Release a note, switching the envelope off at sample time `t` if it was the last one held.
+  ///
+  /// `t` should be within the next block; `Instrument::note_off` uses the start of that block.
+  pub fn note_off_at(&mut self, channel: NoteChannel, t: SampleTime) {
+    if !self.held.contains(&channel) {
+      return;
+    }
+
+    self.held.retain(|&ch| ch != channel);
+
+    if self.held.is_empty() {
+      self.envelope.off(t);
+      self.releasing.push(channel);
+    } else {
+      self.instrument.note_off(channel);
+    }
+  }
+
+  // Release on the wrapped instrument the notes kept alive for the envelope’s tail.
+  fn flush_releasing(&mut self) {
+    for channel in self.releasing.drain(..) {
+      self.instrument.note_off(channel);
+    }
+  }
+}
+
+impl<I, E> Modulable for Enveloped<I, E> where I: Modulable {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.instrument.modulate(param, amount);
+  }
+}
+
+impl<I, E> Instrument for Enveloped<I, E> where I: Instrument, E: Envelope {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    let now = self.now;
+    self.note_on_at(note, channel, expression, now);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    let now = self.now;
+    self.note_off_at(channel, now);
   }
 
   fn is_active(&self, _: Time) -> bool {
-    self.pressed.is_some()
+    !self.held.is_empty() || self.envelope.is_active(self.now)
   }
 
   fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
-    match self.pressed {
-      None => &[],
+    let len = end.0 - start.0;
+
+    self.buffer.clear();
+    self.buffer.extend_from_slice(self.instrument.get_samples(start, end));
+    self.buffer.resize(len, 0.);
+
+    self.envelope.apply(start, &mut self.buffer);
+    self.now = end;
+
+    // the envelope’s tail is over
+    if self.held.is_empty() && !self.envelope.is_active(end) {
+      self.flush_releasing();
+    }
+
+    &self.buffer
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.instrument.get_stereo_samples(start, end, left, right);
+
+    // the envelope is rendered once for both sides
+    self.buffer.clear();
+    self.buffer.resize(end.0 - start.0, 0.);
+    self.envelope.render(start, &mut self.buffer);
+    self.now = end;
+
+    for ((l, r), level) in left.iter_mut().zip(right.iter_mut()).zip(&self.buffer) {
+      *l *= level;
+      *r *= level;
+    }
+
+    // the envelope’s tail is over
+    if self.held.is_empty() && !self.envelope.is_active(end) {
+      self.flush_releasing();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::{A4, C4};
+
+  // An instrument playing a constant signal, whatever the notes.
+  struct Constant {
+    buffer: Vec<Sample>
+  }
+
+  impl Instrument for Constant {
+    fn note_on_with(&mut self, _: Note, _: NoteChannel, _: Expression) {}
+
+    fn note_off(&mut self, _: NoteChannel) {}
+
+    fn is_active(&self, _: Time) -> bool {
+      true
+    }
+
+    fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+      self.buffer.clear();
+      self.buffer.resize(end.0 - start.0, 1.);
+      &self.buffer
+    }
+  }
+
+  #[test]
+  fn enveloped_notes_join_held_ones_without_retriggering() {
+    let rate = SampleRate(1000);
+    let envelope = ADSR::new(0.01, 0.01, 1., 0.01, rate).unwrap();
+    let mut enveloped = Enveloped::new(Constant { buffer: Vec::new() }, envelope);
+
+    enveloped.note_on(C4, NoteChannel::new(0));
+    let before = enveloped.get_samples(SampleTime(0), SampleTime(5))[4];
+
+    enveloped.note_on(A4, NoteChannel::new(1));
+    let after = enveloped.get_samples(SampleTime(5), SampleTime(6))[0];
+
+    assert!(after > before);
+  }
+
+  #[test]
+  fn enveloped_multiplies_instrument_by_envelope() {
+    let rate = SampleRate(1000);
+    let envelope = ADSR::new(0.01, 0.01, 0.5, 0.01, rate).unwrap();
+    let mut reference = envelope.clone();
+    let mut enveloped = Enveloped::new(Constant { buffer: Vec::new() }, envelope);
+
+    enveloped.note_on_at(C4, NoteChannel::new(0), Expression::default(), SampleTime(5));
+    enveloped.note_off_at(NoteChannel::new(0), SampleTime(30));
+    reference.on(SampleTime(5));
+    reference.off(SampleTime(30));
+
+    let samples = enveloped.get_samples(SampleTime(0), SampleTime(50));
+
+    for (i, &sample) in samples.iter().enumerate() {
+      assert_eq!(sample, reference.get(SampleTime(i)), "sample {}", i);
+    }
+
+    assert_eq!(samples[..5], [0.; 5]);
+    assert_eq!(samples[25], 0.5);
+  }
+
+  #[test]
+  fn enveloped_is_active_until_release_tail_ends() {
+    let rate = SampleRate(1000);
+    let envelope = ADSR::new(0.01, 0.01, 1., 0.01, rate).unwrap();
+    let mut enveloped = Enveloped::new(Constant { buffer: Vec::new() }, envelope);
+
+    assert!(!enveloped.is_active(0.));
+
+    enveloped.note_on(C4, NoteChannel::new(0));
+    enveloped.get_samples(SampleTime(0), SampleTime(30));
+    enveloped.note_off(NoteChannel::new(0));
+    assert!(enveloped.is_active(0.));
+
+    // the release lasts 10 samples
+    enveloped.get_samples(SampleTime(30), SampleTime(35));
+    assert!(enveloped.is_active(0.));
+    assert!(enveloped.get_samples(SampleTime(35), SampleTime(36))[0] > 0.);
+
+    enveloped.get_samples(SampleTime(36), SampleTime(50));
+    assert!(!enveloped.is_active(0.));
+  }
+
+  #[test]
+  fn velocity_scales_synth_notes() {
+    let rate = SampleRate(48000);
+    let mut full = Synth::sine(rate);
+    let mut half = Synth::sine(rate);
+
+    full.note_on_with(A4, NoteChannel::new(0), Expression::new(1.));
+    half.note_on_with(A4, NoteChannel::new(0), Expression::new(0.5));
+
+    let full = full.get_samples(SampleTime(0), SampleTime(1000)).to_vec();
+    let half = half.get_samples(SampleTime(0), SampleTime(1000));
+
+    assert!(full.iter().any(|&sample| sample.abs() > 0.5));
+
+    for (i, (&full, &half)) in full.iter().zip(half).enumerate() {
+      assert!((half - full * 0.5).abs() < 1e-6, "sample {}", i);
+    }
+  }
+
+  #[test]
+  fn pan_gains() {
+    assert_eq!(Expression::default().pan_gains(), (1., 1.));
+    assert_eq!(Expression::default().with_pan(-1.).pan_gains(), (1., 0.));
+    assert_eq!(Expression::default().with_pan(0.).pan_gains(), (1., 1.));
+    assert_eq!(Expression::default().with_pan(1.).pan_gains(), (0., 1.));
+  }
+
+  #[test]
+  fn pan_places_synth_notes() {
+    let rate = SampleRate(48000);
+
+    for &(pan, (left_gain, right_gain)) in &[(-1., (1., 0.)), (0., (1., 1.)), (1., (0., 1.))] {
+      let mut mono = Synth::sine(rate);
+      let mut stereo = Synth::sine(rate);
+      let mut left = [0.; 1000];
+      let mut right = [0.; 1000];
+
+      mono.note_on(A4, NoteChannel::new(0));
+      stereo.note_on_with(A4, NoteChannel::new(0), Expression::default().with_pan(pan));
+      stereo.get_stereo_samples(SampleTime(0), SampleTime(1000), &mut left, &mut right);
 
-      Some(ref pressed) => {
-        self.oscillator.sample(start, end, pressed.note.frequency())
+      for (i, &sample) in mono.get_samples(SampleTime(0), SampleTime(1000)).iter().enumerate() {
+        assert!((left[i] - sample * left_gain).abs() < 1e-6, "pan {}, sample {}", pan, i);
+        assert!((right[i] - sample * right_gain).abs() < 1e-6, "pan {}, sample {}", pan, i);
       }
     }
   }
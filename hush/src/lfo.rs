@@ -0,0 +1,268 @@
+//! Low-frequency oscillators.
+//!
+//! LFOs are slow oscillators used as modulation sources rather than heard directly: vibrato
+//! (modulating pitch), tremolo (modulating amplitude), filter sweeps, etc. They’re rendered into
+//! control buffers holding one value per sample.
+
+use alloc::vec::Vec;
+
+use hertz::Hertz;
+use oscillator::{Oscillator, Wave, sawtooth_wave, sine_wave, square_wave, triangle_wave};
+use random::Random;
+use sample::Sample;
+use time::{SampleRate, SampleTime};
+
+/// Shape of an LFO.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LfoShape {
+  Sine,
+  Triangle,
+  Square,
+  Sawtooth,
+  /// A new random value is picked at the beginning of each period and held until the next one.
+  SampleAndHold
+}
+
+/// Rate of an LFO.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfoRate {
+  /// Free-running rate.
+  Hertz(Hertz),
+  /// Rate synchronized to a tempo: one period lasts `beats` beats at `bpm` beats per minute.
+  Sync { bpm: f32, beats: f32 }
+}
+
+impl LfoRate {
+  /// Frequency of the LFO.
+  pub fn frequency(&self) -> Hertz {
+    match *self {
+      LfoRate::Hertz(freq) => freq,
+      LfoRate::Sync { bpm, beats } => bpm / (60. * beats)
+    }
+  }
+}
+
+// Wave of an LFO; sample and hold needs some state.
+#[derive(Clone, Debug)]
+struct LfoWave {
+  shape: LfoShape,
+  random: Random,
+  held: Sample,
+  // phase of the previous sample
+  phase: Hertz
+}
+
+impl Wave for LfoWave {
+  fn sample(&mut self, t: Hertz, _: Hertz) -> Sample {
+    match self.shape {
+      LfoShape::Sine => sine_wave(t),
+      LfoShape::Triangle => triangle_wave(t),
+      LfoShape::Square => square_wave(t),
+      LfoShape::Sawtooth => sawtooth_wave(t),
+      LfoShape::SampleAndHold => {
+        // the phase wrapped: a new period just started
+        if t < self.phase {
+          self.held = self.random.next_bipolar();
+        }
+
+        self.phase = t;
+        self.held
+      }
+    }
+  }
+}
+
+/// Default seed of sample-and-hold LFOs.
+pub const DEFAULT_SEED: u32 = 0x2545_f491;
+
+/// A low-frequency oscillator.
+///
+/// The output of an LFO is bipolar: it lies in `[-depth; depth]`.
+pub struct Lfo {
+  oscillator: Oscillator<LfoWave>,
+  rate: LfoRate,
+  depth: f32,
+  phase_offset: f32,
+  seed: u32,
+  buffer: Vec<f32>
+}
+
+impl Lfo {
+  pub fn new(shape: LfoShape, rate: LfoRate, sample_rate: SampleRate) -> Self {
+    let wave = LfoWave {
+      shape,
+      random: Random::new(DEFAULT_SEED),
+      held: 0.,
+      phase: 0.
+    };
+
+    let mut lfo = Lfo {
+      oscillator: Oscillator::new(wave, sample_rate),
+      rate,
+      depth: 1.,
+      phase_offset: 0.,
+      seed: DEFAULT_SEED,
+      buffer: Vec::new()
+    };
+
+    lfo.reset();
+    lfo
+  }
+
+  /// Change the depth (1 by default).
+  pub fn with_depth(mut self, depth: f32) -> Self {
+    self.depth = depth;
+    self
+  }
+
+  /// Change the phase offset (0 by default), normalized in `[0; 1[`.
+  ///
+  /// The LFO is reset to its phase offset.
+  pub fn with_phase_offset(mut self, phase_offset: f32) -> Self {
+    self.phase_offset = phase_offset;
+    self.reset();
+    self
+  }
+
+  /// Change the seed of sample-and-hold LFOs.
+  ///
+  /// The LFO is reset.
+  pub fn with_seed(mut self, seed: u32) -> Self {
+    self.seed = seed;
+    self.reset();
+    self
+  }
+
+  /// Rate of the LFO.
+  pub fn rate(&self) -> LfoRate {
+    self.rate
+  }
+
+  /// Change the rate of the LFO.
+  ///
+  /// The phase is kept, so changing the rate never makes the LFO jump.
+  pub fn set_rate(&mut self, rate: LfoRate) {
+    self.rate = rate;
+  }
+
+  /// Depth of the LFO.
+  pub fn depth(&self) -> f32 {
+    self.depth
+  }
+
+  /// Change the depth of the LFO.
+  pub fn set_depth(&mut self, depth: f32) {
+    self.depth = depth;
+  }
+
+  /// Restart the LFO at its phase offset – typically when a note is pressed.
+  ///
+  /// Sample-and-hold LFOs draw their first value from their seed right away, and the next ones
+  /// each time a period ends.
+  pub fn reset(&mut self) {
+    self.oscillator.set_phase(self.phase_offset);
+
+    let phase = self.oscillator.phase();
+    let wave = self.oscillator.wave_mut();
+    wave.random = Random::new(self.seed);
+    wave.held = wave.random.next_bipolar();
+    wave.phase = phase;
+  }
+
+  /// Produce the next value of the LFO.
+  pub fn next_value(&mut self) -> f32 {
+    let freq = self.rate.frequency();
+    self.oscillator.next_sample(freq) * self.depth
+  }
+
+  /// Render the LFO from `start` to `end` into a control buffer.
+  ///
+  /// Only the number of values is taken from `start` and `end`: the LFO carries on from where the
+  /// previous call left it.
+  pub fn render(&mut self, start: SampleTime, end: SampleTime) -> &[f32] {
+    assert!(end >= start);
+
+    self.buffer.clear();
+
+    for _ in start.0 .. end.0 {
+      let value = self.next_value();
+      self.buffer.push(value);
+    }
+
+    &self.buffer
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::vec::Vec;
+
+  const RATE: SampleRate = SampleRate(48000);
+
+  fn values(lfo: &mut Lfo, count: usize) -> Vec<f32> {
+    (0..count).map(|_| lfo.next_value()).collect()
+  }
+
+  #[test]
+  fn sync_rate_follows_tempo() {
+    // a quarter of a bar at 120 BPM: half a second
+    let rate = LfoRate::Sync { bpm: 120., beats: 1. };
+    let mut lfo = Lfo::new(LfoShape::Sine, rate, RATE);
+
+    assert_eq!(rate.frequency(), 2.);
+
+    let values = values(&mut lfo, 48000);
+
+    for i in 0 .. 24000 {
+      assert!((values[i] - values[i + 24000]).abs() < 1e-2, "sample {}", i);
+    }
+  }
+
+  #[test]
+  fn depth_scales_values() {
+    let mut lfo = Lfo::new(LfoShape::Sine, LfoRate::Hertz(1.), RATE).with_depth(0.25);
+    let values = values(&mut lfo, 48000);
+    let max = values.iter().cloned().fold(0., f32::max);
+    let min = values.iter().cloned().fold(0., f32::min);
+
+    assert!((max - 0.25).abs() < 1e-4 && (min + 0.25).abs() < 1e-4, "{} to {}", min, max);
+  }
+
+  #[test]
+  fn phase_offset_shifts_start() {
+    let mut lfo = Lfo::new(LfoShape::Sine, LfoRate::Hertz(1.), RATE).with_phase_offset(0.25);
+
+    assert!((lfo.next_value() - 1.).abs() < 1e-6);
+
+    lfo.next_value();
+    lfo.reset();
+
+    assert!((lfo.next_value() - 1.).abs() < 1e-6);
+  }
+
+  #[test]
+  fn sample_and_hold_repeats_after_reset() {
+    for &offset in &[0., 0.5] {
+      let mut lfo = Lfo::new(LfoShape::SampleAndHold, LfoRate::Hertz(100.), RATE)
+        .with_phase_offset(offset)
+        .with_seed(42);
+      let first = values(&mut lfo, 4800);
+
+      lfo.reset();
+      assert_eq!(values(&mut lfo, 4800), first);
+
+      // the first value is drawn from the seed, whatever the offset
+      assert_eq!(first[0], Random::new(42).next_bipolar());
+    }
+  }
+
+  #[test]
+  fn sample_and_hold_draws_once_per_period() {
+    let mut lfo = Lfo::new(LfoShape::SampleAndHold, LfoRate::Hertz(100.), RATE);
+    let values = values(&mut lfo, 4800);
+    let changes = values.windows(2).filter(|w| w[0] != w[1]).count();
+
+    assert_eq!(changes, 9);
+  }
+}
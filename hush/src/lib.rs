@@ -27,6 +27,46 @@
 //! This rule of normalization is used pretty much everywhere in the crate, so ensure you are
 //! completely comfortable with the idea.
 //!
+//! ## Unison
+//!
+//! Synth voices can stack detuned copies of their oscillator – with reset, spread or random phases –
+//! and spread them across the stereo field, for supersaws and other thick leads. Instruments can
+//! render stereo samples; mono instruments play the same signal on both sides.
+//!
+//! ## Combinators
+//!
+//! Waves can be combined: hard sync resets a wave’s phase at each period of the oscillator, ring
+//! modulation multiplies two waves and mixing crossfades between them.
+//!
+//! ## Wavetables
+//!
+//! Wavetable oscillators play single-cycle frames – loaded or generated procedurally – and can morph
+//! between them. Tables are mip-mapped per octave so that they don’t alias.
+//!
+//! ## Filters
+//!
+//! Resonant state-variable filters and biquads (low-pass, high-pass, band-pass, notch, peaking and
+//! shelves) process blocks of samples in place. Their cutoff can be modulated per sample without
+//! zipper noise.
+//!
+//! ## Noise
+//!
+//! White, pink, brown and NES-style LFSR noise generators are available. They’re seeded, so that
+//! the same seed always gives the same noise, and can be played by synths or used as modulation
+//! sources.
+//!
+//! ## Low-frequency oscillators
+//!
+//! LFOs are slow oscillators used to modulate parameters over time – vibrato, tremolo, filter
+//! sweeps, etc. They can be free-running or synchronized to a tempo, and are rendered into
+//! per-sample control buffers.
+//!
+//! ## Modulation
+//!
+//! Envelopes, LFOs, noises, note expression, note number and random values can be routed to named
+//! parameters of instruments and effects through a modulation matrix, each route with its own
+//! depth.
+//!
 //! ## Instruments
 //!
 //! The basic and primitive block of this crate is an instrument. An instrument is an audio signal
@@ -37,6 +77,9 @@
 //! The audio signal output from instruments can then be taken out and passed to other audio blocks
 //! for further audio processing.
 //!
+//! Notes can be played with an expression: a velocity and, optionally, a pressure, a timbre and a
+//! pan, that instruments map onto their amplitude, filter cutoff, brightness, etc.
+//!
 //! ## Multi-channel instruments
 //!
 //! By default, all instruments support the concept of multi-channeling. This allows for holding
@@ -47,12 +90,55 @@
 //! When asking an instrument to play a note, you can optionally ask the instrument to play the note
 //! on a given `NoteChannel`, allowing to play several notes at the same time.
 //!
+//! Polyphonic instruments play each note on a *voice*. They have a limited number of voices; when
+//! all of them are busy, one is stolen according to a `VoiceStealing` policy (oldest voice, quietest
+//! voice or voice already playing the same note).
+//!
+//! ## Subtractive synthesis
+//!
+//! Subtractive voices chain oscillators, a resonant filter driven by its own envelope and an
+//! amplifier driven by an ADSR. Patches describing them can be written by hand or started from the
+//! bass, lead and pad presets.
+//!
+//! ## FM synthesis
+//!
+//! FM voices are made of four sine operators, each with its own frequency ratio, level and
+//! envelope, wired together by one of several algorithms. The first operator can modulate itself
+//! through feedback.
+//!
+//! ## Additive synthesis
+//!
+//! Additive sounds sum sine partials, each with its own frequency ratio, amplitude and optional
+//! envelope. Organs and bells can be described compactly as lists of partials; partials above
+//! Nyquist are dropped automatically.
+//!
+//! ## Physical modelling
+//!
+//! Plucked strings are modelled after the Karplus–Strong algorithm, with brightness, decay and pluck
+//! position parameters.
+//!
+//! ## Samplers
+//!
+//! Samplers play recorded audio back at the pitch of the notes they’re given, with an optional
+//! sustain loop and nearest, linear, cubic or windowed sinc interpolation.
+//!
+//! ## Granular synthesis
+//!
+//! Granular synths play clouds of short, windowed grains taken from a recording or from the rendered
+//! output of an oscillator, with control over the grain size, density, position and jitter.
+//!
+//! ## Drums
+//!
+//! A drum machine synthesizes kicks, snares, claps, hi-hats and toms – no samples needed – and plays
+//! them from notes in the General MIDI percussion layout.
+//!
 //! ## Envelopes
 //!
 //! Envelopes are typically used to modify the volume of an audio signal on the fly. This crate
 //! gives you
 //! [ADSR](https://en.wikipedia.org/wiki/Synthesizer#Attack_Decay_Sustain_Release_(ADSR)_envelope)
-//! envelopes, that can be parametered to achieve interesting sound effects.
+//! envelopes, that can be parametered to achieve interesting sound effects, as well as multi-stage
+//! envelopes made of arbitrary breakpoints (DAHDSR, AHD, looping envelopes, etc.).
 //!
 //! # Special thanks
 //!
@@ -66,10 +152,26 @@
 
 extern crate alloc;
 
+pub mod additive;
+pub mod combinator;
+pub mod drum;
 pub mod envelope;
+pub mod filter;
+pub mod fm;
+pub mod granular;
 pub mod instrument;
 pub mod hertz;
+pub mod lfo;
+pub mod modulation;
+pub mod noise;
 pub mod note;
 pub mod oscillator;
+pub mod pluck;
+mod random;
 pub mod sample;
+pub mod sampler;
+pub mod subtractive;
 pub mod time;
+pub mod unison;
+pub mod voice;
+pub mod wavetable;
@@ -0,0 +1,489 @@
+//! Modulation matrix.
+//!
+//! A modulation matrix connects modulation *sources* – envelopes, LFOs, noises, note expression, note
+//! number, random values – to named parameter *destinations* of instruments and effects. Each connection
+//! (a route) has its own depth. All routes going to the same destination are summed up and the
+//! result is handed to the target as a modulation amount, on top of its base value.
+//!
+//! Targets implement `Modulable`; the destinations they understand are listed in their
+//! documentation. The most common ones have constants in this module.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use envelope::Envelope;
+use instrument::{Expression, Instrument, NoteChannel};
+use lfo::Lfo;
+use noise::Noise;
+use note::Note;
+use random::Random;
+use sample::Sample;
+use time::{SampleTime, Time};
+
+/// Pitch destination, in semitones.
+pub const PITCH: &str = "pitch";
+
+/// Amplitude destination, as a gain offset: an amount of -1 silences, 0 leaves untouched, 1
+/// doubles.
+pub const AMPLITUDE: &str = "amplitude";
+
+/// Pulse width destination, as an offset of the duty cycle.
+pub const DUTY: &str = "duty";
+
+/// Wavetable morph destination, as an offset of the morph position.
+pub const MORPH: &str = "morph";
+
+/// Filter cutoff destination, in octaves.
+pub const CUTOFF: &str = "cutoff";
+
+/// Filter resonance destination, as an offset.
+pub const RESONANCE: &str = "resonance";
+
+/// Default seed of the random source.
+pub const DEFAULT_SEED: u32 = 0x5bd1_e995;
+
+/// Something whose parameters can be modulated.
+pub trait Modulable {
+  /// Set the modulation amount of a parameter.
+  ///
+  /// The amount is applied on top of the parameter’s base value and replaces the previous amount.
+  /// Unknown parameters are ignored.
+  fn modulate(&mut self, param: &str, amount: f32);
+}
+
+/// A modulation source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModSource {
+  /// Envelope of the matrix, as returned by `ModMatrix::add_envelope`; in `[0; 1]`.
+  Envelope(usize),
+  /// LFO of the matrix, as returned by `ModMatrix::add_lfo`; in `[-depth; depth]`.
+  Lfo(usize),
+  /// Noise of the matrix, as returned by `ModMatrix::add_noise`; in `[-1; 1]`.
+  Noise(usize),
+  /// Velocity of the last note pressed, in `[0; 1]`.
+  Velocity,
+  /// Pressure of the last note pressed, in `[0; 1]`; 0 if the note has none.
+  Pressure,
+  /// Timbre of the last note pressed, in `[-1; 1]`; 0 if the note has none.
+  Timbre,
+  /// Number of the last note pressed, mapped from `[0; 127]` to `[0; 1]`.
+  NoteNumber,
+  /// A random value in `[-1; 1]` picked each time a note is pressed.
+  Random
+}
+
+/// A connection from a source to a destination.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+  pub source: ModSource,
+  pub destination: &'static str,
+  pub depth: f32
+}
+
+/// A modulation matrix.
+///
+/// The matrix owns the envelopes, LFOs and noises used as sources. Envelopes are switched on and
+/// off with the notes; LFOs run freely; noises produce a new value for each evaluated block.
+pub struct ModMatrix {
+  envelopes: Vec<Box<dyn Envelope>>,
+  lfos: Vec<Lfo>,
+  // value of each LFO for the block being evaluated
+  lfo_values: Vec<f32>,
+  noises: Vec<Noise>,
+  // value of each noise for the block being evaluated
+  noise_values: Vec<f32>,
+  routes: Vec<Route>,
+  velocity: f32,
+  pressure: f32,
+  timbre: f32,
+  note_number: f32,
+  random: Random,
+  random_value: f32,
+  // amount of each destination, reused between evaluations
+  amounts: Vec<(&'static str, f32)>
+}
+
+impl ModMatrix {
+  pub fn new() -> Self {
+    ModMatrix {
+      envelopes: Vec::new(),
+      lfos: Vec::new(),
+      lfo_values: Vec::new(),
+      noises: Vec::new(),
+      noise_values: Vec::new(),
+      routes: Vec::new(),
+      velocity: 1.,
+      pressure: 0.,
+      timbre: 0.,
+      note_number: 0.,
+      random: Random::new(DEFAULT_SEED),
+      random_value: 0.,
+      amounts: Vec::new()
+    }
+  }
+
+  /// Change the seed of the random source.
+  pub fn with_seed(mut self, seed: u32) -> Self {
+    self.random = Random::new(seed);
+    self
+  }
+
+  /// Add an envelope and get the source it’s available as.
+  pub fn add_envelope<E>(&mut self, envelope: E) -> ModSource where E: 'static + Envelope {
+    self.envelopes.push(Box::new(envelope));
+    ModSource::Envelope(self.envelopes.len() - 1)
+  }
+
+  /// Add an LFO and get the source it’s available as.
+  pub fn add_lfo(&mut self, lfo: Lfo) -> ModSource {
+    self.lfos.push(lfo);
+    ModSource::Lfo(self.lfos.len() - 1)
+  }
+
+  /// Add a noise and get the source it’s available as.
+  pub fn add_noise(&mut self, noise: Noise) -> ModSource {
+    self.noises.push(noise);
+    ModSource::Noise(self.noises.len() - 1)
+  }
+
+  /// Connect a source to a destination with a given depth.
+  pub fn route(&mut self, source: ModSource, destination: &'static str, depth: f32) {
+    self.routes.push(Route { source, destination, depth });
+  }
+
+  /// All routes.
+  pub fn routes(&self) -> &[Route] {
+    &self.routes
+  }
+
+  /// Change the depth of all routes from a source to a destination.
+  pub fn set_depth(&mut self, source: ModSource, destination: &str, depth: f32) {
+    for route in self.routes.iter_mut().filter(|r| r.source == source && r.destination == destination) {
+      route.depth = depth;
+    }
+  }
+
+  /// Set the velocity source (1 by default).
+  pub fn set_velocity(&mut self, velocity: f32) {
+    self.velocity = velocity;
+  }
+
+  /// Set the velocity, pressure and timbre sources from the expression of a note.
+  pub fn set_expression(&mut self, expression: &Expression) {
+    self.velocity = expression.velocity;
+    self.pressure = expression.pressure.unwrap_or(0.);
+    self.timbre = expression.timbre.unwrap_or(0.);
+  }
+
+  /// Notify the matrix that a note is pressed at a given sample time.
+  pub fn note_on(&mut self, note: Note, t: SampleTime) {
+    self.note_number = note.number() / 127.;
+    self.random_value = self.random.next_bipolar();
+
+    for envelope in &mut self.envelopes {
+      envelope.on(t);
+    }
+  }
+
+  /// Notify the matrix that the notes are released at a given sample time.
+  pub fn note_off(&mut self, t: SampleTime) {
+    for envelope in &mut self.envelopes {
+      envelope.off(t);
+    }
+  }
+
+  /// Evaluate the matrix for the block of `len` samples starting at `start` and modulate `target`.
+  ///
+  /// Sources are sampled at the beginning of the block; LFOs then advance by `len` samples. Use
+  /// blocks of one sample for per-sample modulation.
+  pub fn evaluate<M>(&mut self, start: SampleTime, len: usize, target: &mut M) where M: Modulable + ?Sized {
+    let end = SampleTime(start.0 + len);
+
+    // sample every LFO once, however many routes use it
+    self.lfo_values.clear();
+
+    for lfo in &mut self.lfos {
+      let value = lfo.render(start, end).first().cloned().unwrap_or(0.);
+      self.lfo_values.push(value);
+    }
+
+    self.noise_values.clear();
+
+    for noise in &mut self.noises {
+      self.noise_values.push(noise.next_sample());
+    }
+
+    self.amounts.clear();
+
+    for route in &self.routes {
+      let value = match route.source {
+        ModSource::Envelope(i) => self.envelopes.get(i).map_or(0., |envelope| envelope.get(start)),
+        ModSource::Lfo(i) => self.lfo_values.get(i).cloned().unwrap_or(0.),
+        ModSource::Noise(i) => self.noise_values.get(i).cloned().unwrap_or(0.),
+        ModSource::Velocity => self.velocity,
+        ModSource::Pressure => self.pressure,
+        ModSource::Timbre => self.timbre,
+        ModSource::NoteNumber => self.note_number,
+        ModSource::Random => self.random_value
+      };
+
+      let amount = value * route.depth;
+
+      match self.amounts.iter_mut().find(|&&mut (destination, _)| destination == route.destination) {
+        Some(&mut (_, ref mut total)) => *total += amount,
+        None => self.amounts.push((route.destination, amount))
+      }
+    }
+
+    for &(destination, amount) in &self.amounts {
+      target.modulate(destination, amount);
+    }
+  }
+}
+
+impl Default for ModMatrix {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// An instrument driven by a modulation matrix.
+///
+/// Before rendering each block of `block_size` samples, the matrix is evaluated and the wrapped
+/// instrument modulated accordingly. A block size of 1 gives per-sample modulation; bigger blocks are
+/// cheaper.
+///
+/// The matrix’ envelopes are switched on whenever a note is pressed and switched off when the last
+/// held note is released. The expression of each note pressed feeds the matrix’ velocity, pressure
+/// and timbre sources.
+pub struct Modulated<I> {
+  instrument: I,
+  matrix: ModMatrix,
+  block_size: usize,
+  // channels currently held
+  held: Vec<NoteChannel>,
+  // sample time at which the next block starts
+  now: SampleTime,
+  buffer: Vec<Sample>
+}
+
+/// Default block size of `Modulated` instruments.
+pub const DEFAULT_BLOCK_SIZE: usize = 32;
+
+impl<I> Modulated<I> where I: Instrument + Modulable {
+  pub fn new(instrument: I, matrix: ModMatrix) -> Self {
+    Modulated {
+      instrument,
+      matrix,
+      block_size: DEFAULT_BLOCK_SIZE,
+      held: Vec::new(),
+      now: SampleTime(0),
+      buffer: Vec::new()
+    }
+  }
+
+  /// Change the size of the blocks the matrix is evaluated for (at least 1).
+  pub fn with_block_size(mut self, block_size: usize) -> Self {
+    self.block_size = block_size.max(1);
+    self
+  }
+
+  /// Wrapped instrument.
+  pub fn instrument(&self) -> &I {
+    &self.instrument
+  }
+
+  /// Wrapped instrument.
+  pub fn instrument_mut(&mut self) -> &mut I {
+    &mut self.instrument
+  }
+
+  /// Modulation matrix.
+  pub fn matrix(&self) -> &ModMatrix {
+    &self.matrix
+  }
+
+  /// Modulation matrix.
+  pub fn matrix_mut(&mut self) -> &mut ModMatrix {
+    &mut self.matrix
+  }
+}
+
+impl<I> Instrument for Modulated<I> where I: Instrument + Modulable {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    if !self.held.contains(&channel) {
+      self.held.push(channel);
+    }
+
+    self.matrix.set_expression(&expression);
+    self.matrix.note_on(note, self.now);
+    self.instrument.note_on_with(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.held.retain(|&ch| ch != channel);
+
+    if self.held.is_empty() {
+      self.matrix.note_off(self.now);
+    }
+
+    self.instrument.note_off(channel);
+  }
+
+  fn is_active(&self, t: Time) -> bool {
+    self.instrument.is_active(t)
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    assert!(end >= start);
+
+    self.buffer.clear();
+
+    let mut block_start = start.0;
+
+    while block_start < end.0 {
+      let block_end = (block_start + self.block_size).min(end.0);
+      let len = block_end - block_start;
+
+      self.matrix.evaluate(SampleTime(block_start), len, &mut self.instrument);
+
+      let samples = self.instrument.get_samples(SampleTime(block_start), SampleTime(block_end));
+      self.buffer.extend_from_slice(samples);
+      // instruments that are silent may give back fewer samples
+      self.buffer.resize(block_end - start.0, 0.);
+
+      block_start = block_end;
+    }
+
+    self.now = end;
+
+    &self.buffer
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    assert!(end >= start);
+
+    let mut block_start = start.0;
+
+    while block_start < end.0 {
+      let block_end = (block_start + self.block_size).min(end.0);
+      let (from, to) = (block_start - start.0, block_end - start.0);
+
+      self.matrix.evaluate(SampleTime(block_start), block_end - block_start, &mut self.instrument);
+      self.instrument.get_stereo_samples(
+        SampleTime(block_start),
+        SampleTime(block_end),
+        &mut left[from..to],
+        &mut right[from..to]
+      );
+
+      block_start = block_end;
+    }
+
+    self.now = end;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use envelope::ADSR;
+  use lfo::{LfoRate, LfoShape};
+  use note::A4;
+  use time::SampleRate;
+
+  const RATE: SampleRate = SampleRate(1000);
+
+  // An instrument recording its modulations and playing its pitch amount.
+  struct Probe {
+    pitch: f32,
+    cutoff: f32,
+    buffer: Vec<Sample>
+  }
+
+  impl Probe {
+    fn new() -> Self {
+      Probe { pitch: 0., cutoff: 0., buffer: Vec::new() }
+    }
+  }
+
+  impl Modulable for Probe {
+    fn modulate(&mut self, param: &str, amount: f32) {
+      match param {
+        PITCH => self.pitch = amount,
+        CUTOFF => self.cutoff = amount,
+        _ => ()
+      }
+    }
+  }
+
+  impl Instrument for Probe {
+    fn note_on_with(&mut self, _: Note, _: NoteChannel, _: Expression) {}
+
+    fn note_off(&mut self, _: NoteChannel) {}
+
+    fn is_active(&self, _: Time) -> bool {
+      true
+    }
+
+    fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+      self.buffer.clear();
+      self.buffer.resize(end.0 - start.0, self.pitch);
+      &self.buffer
+    }
+  }
+
+  #[test]
+  fn lfo_route_modulates_pitch_by_depth() {
+    let mut matrix = ModMatrix::new();
+    let mut probe = Probe::new();
+
+    // a sine LFO starting at its peak
+    let lfo = matrix.add_lfo(Lfo::new(LfoShape::Sine, LfoRate::Hertz(1.), RATE).with_depth(0.5).with_phase_offset(0.25));
+    matrix.route(lfo, PITCH, 12.);
+    matrix.evaluate(SampleTime(0), 1, &mut probe);
+
+    assert!((probe.pitch - 6.).abs() < 1e-5, "{}", probe.pitch);
+    assert_eq!(probe.cutoff, 0.);
+  }
+
+  #[test]
+  fn envelope_route_modulates_cutoff_by_depth() {
+    let mut matrix = ModMatrix::new();
+    let mut probe = Probe::new();
+
+    // 10 ms of attack then 10 ms of decay to 0.5
+    let envelope = matrix.add_envelope(ADSR::new(0.01, 0.01, 0.5, 0.01, RATE).unwrap());
+    matrix.route(envelope, CUTOFF, 2.);
+    matrix.note_on(A4, SampleTime(0));
+
+    matrix.evaluate(SampleTime(0), 1, &mut probe);
+    assert_eq!(probe.cutoff, 0.);
+
+    matrix.evaluate(SampleTime(10), 1, &mut probe);
+    assert!((probe.cutoff - 2.).abs() < 1e-5, "{}", probe.cutoff);
+
+    matrix.evaluate(SampleTime(50), 1, &mut probe);
+    assert!((probe.cutoff - 1.).abs() < 1e-5, "{}", probe.cutoff);
+    assert_eq!(probe.pitch, 0.);
+  }
+
+  #[test]
+  fn modulated_evaluates_matrix_per_block() {
+    let mut matrix = ModMatrix::new();
+
+    // 16 ms of attack: the envelope rises by 0.25 every 4 samples
+    let envelope = matrix.add_envelope(ADSR::new(0.016, 0.01, 1., 0.01, RATE).unwrap());
+    matrix.route(envelope, PITCH, 1.);
+
+    let mut modulated = Modulated::new(Probe::new(), matrix).with_block_size(4);
+    modulated.note_on(A4, NoteChannel::new(0));
+
+    let samples = modulated.get_samples(SampleTime(0), SampleTime(16));
+
+    for (i, &sample) in samples.iter().enumerate() {
+      let expected = (i / 4) as f32 * 0.25;
+      assert!((sample - expected).abs() < 1e-5, "sample {}: {} instead of {}", i, sample, expected);
+    }
+  }
+}
@@ -0,0 +1,243 @@
+//! Noise generators.
+//!
+//! Noise is the raw material of hi-hats, snares, wind and all kinds of effects. All generators are
+//! seeded: the same seed always yields the same noise, so that a piece sounds identical on every
+//! run.
+//!
+//! A `Noise` can be sampled on its own, used as the wave of an `Oscillator` – and hence as the
+//! source of a `Synth` – or used as a modulation source.
+
+use hertz::Hertz;
+use oscillator::Wave;
+use random::Random;
+use sample::Sample;
+
+/// Default seed of noise generators.
+pub const DEFAULT_SEED: u32 = 0x68e3_1da4;
+
+/// Color of a noise.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NoiseColor {
+  /// Flat spectrum.
+  White,
+  /// -3 dB per octave: as much energy in every octave.
+  Pink,
+  /// -6 dB per octave: a deep rumble.
+  Brown,
+  /// NES-style 15-bit linear-feedback shift register, giving a harsh, metallic noise.
+  Lfsr,
+  /// NES-style LFSR in its short mode (93-step period), giving a buzzy, pitched noise.
+  LfsrShort
+}
+
+/// A noise generator.
+///
+/// The output lies in `[-1; 1]`.
+///
+/// When used as the wave of an `Oscillator`, white, pink and brown noises produce a new value at
+/// each sample, whatever the frequency. LFSR noises are clocked by the oscillator instead: the
+/// register is shifted once per period, so that the frequency sets the pitch of the noise, as on the
+/// NES.
+#[derive(Clone, Debug)]
+pub struct Noise {
+  color: NoiseColor,
+  seed: u32,
+  random: Random,
+  // pink noise filter state
+  pink: [f32; 7],
+  // brown noise integrator state
+  brown: f32,
+  lfsr: u16
+}
+
+impl Noise {
+  pub fn new(color: NoiseColor) -> Self {
+    Self::with_seed(color, DEFAULT_SEED)
+  }
+
+  pub fn white() -> Self {
+    Self::new(NoiseColor::White)
+  }
+
+  pub fn pink() -> Self {
+    Self::new(NoiseColor::Pink)
+  }
+
+  pub fn brown() -> Self {
+    Self::new(NoiseColor::Brown)
+  }
+
+  pub fn lfsr() -> Self {
+    Self::new(NoiseColor::Lfsr)
+  }
+
+  pub fn lfsr_short() -> Self {
+    Self::new(NoiseColor::LfsrShort)
+  }
+
+  /// Create a noise generator from a seed.
+  pub fn with_seed(color: NoiseColor, seed: u32) -> Self {
+    let random = Random::new(seed);
+    // the register must not be all zeros
+    let lfsr = (random.clone().next_u32() & 0x7fff) as u16;
+
+    Noise {
+      color,
+      seed,
+      random,
+      pink: [0.; 7],
+      brown: 0.,
+      lfsr: if lfsr == 0 { 1 } else { lfsr }
+    }
+  }
+
+  /// Color of the noise.
+  pub fn color(&self) -> NoiseColor {
+    self.color
+  }
+
+  /// Seed the noise was created with.
+  pub fn seed(&self) -> u32 {
+    self.seed
+  }
+
+  /// Restart the noise from its seed.
+  pub fn reset(&mut self) {
+    *self = Self::with_seed(self.color, self.seed);
+  }
+
+  /// Produce the next value of the noise.
+  ///
+  /// LFSR noises are shifted once.
+  pub fn next_sample(&mut self) -> Sample {
+    match self.color {
+      NoiseColor::White => self.random.next_bipolar(),
+      NoiseColor::Pink => self.next_pink(),
+      NoiseColor::Brown => self.next_brown(),
+      NoiseColor::Lfsr => self.shift_lfsr(1),
+      NoiseColor::LfsrShort => self.shift_lfsr(6)
+    }
+  }
+
+  // Current output of the LFSR.
+  fn lfsr_output(&self) -> Sample {
+    if self.lfsr & 1 == 0 { 1. } else { -1. }
+  }
+
+  // Shift the LFSR, the feedback being bit 0 xored with bit `tap`.
+  fn shift_lfsr(&mut self, tap: u16) -> Sample {
+    let feedback = (self.lfsr ^ (self.lfsr >> tap)) & 1;
+    self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+    self.lfsr_output()
+  }
+
+  // Paul Kellet’s refined pink noise filter.
+  fn next_pink(&mut self) -> Sample {
+    let white = self.random.next_bipolar();
+    let b = &mut self.pink;
+
+    b[0] = 0.99886 * b[0] + white * 0.0555179;
+    b[1] = 0.99332 * b[1] + white * 0.0750759;
+    b[2] = 0.96900 * b[2] + white * 0.1538520;
+    b[3] = 0.86650 * b[3] + white * 0.3104856;
+    b[4] = 0.55000 * b[4] + white * 0.5329522;
+    b[5] = -0.7616 * b[5] - white * 0.0168980;
+
+    let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+    b[6] = white * 0.115926;
+
+    (pink * 0.15).max(-1.).min(1.)
+  }
+
+  // Leaky integration of white noise.
+  fn next_brown(&mut self) -> Sample {
+    let white = self.random.next_bipolar();
+
+    self.brown = (self.brown + 0.02 * white) / 1.02;
+    (self.brown * 3.5).max(-1.).min(1.)
+  }
+}
+
+impl Wave for Noise {
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    match self.color {
+      NoiseColor::Lfsr | NoiseColor::LfsrShort => {
+        // a new period just started
+        if t < dt {
+          self.next_sample()
+        } else {
+          self.lfsr_output()
+        }
+      }
+
+      _ => self.next_sample()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::vec::Vec;
+
+  const COLORS: [NoiseColor; 5] = [
+    NoiseColor::White,
+    NoiseColor::Pink,
+    NoiseColor::Brown,
+    NoiseColor::Lfsr,
+    NoiseColor::LfsrShort
+  ];
+
+  fn samples(color: NoiseColor, seed: u32) -> Vec<Sample> {
+    let mut noise = Noise::with_seed(color, seed);
+    (0..1024).map(|_| noise.next_sample()).collect()
+  }
+
+  // Number of shifts it takes the register to come back to its first state.
+  fn lfsr_period(color: NoiseColor) -> usize {
+    let mut noise = Noise::new(color);
+    noise.lfsr = 1;
+
+    let mut period = 0;
+
+    loop {
+      noise.next_sample();
+      period += 1;
+
+      if noise.lfsr == 1 {
+        return period;
+      }
+    }
+  }
+
+  #[test]
+  fn same_seed_gives_same_noise() {
+    for &color in &COLORS {
+      assert_eq!(samples(color, 42), samples(color, 42), "{:?}", color);
+    }
+  }
+
+  #[test]
+  fn different_seeds_give_different_noises() {
+    for &color in &COLORS {
+      assert_ne!(samples(color, 42), samples(color, 43), "{:?}", color);
+    }
+  }
+
+  #[test]
+  fn reset_restarts_noise() {
+    let mut noise = Noise::with_seed(NoiseColor::Pink, 42);
+
+    noise.next_sample();
+    noise.reset();
+
+    let restarted: Vec<Sample> = (0..1024).map(|_| noise.next_sample()).collect();
+    assert_eq!(restarted, samples(NoiseColor::Pink, 42));
+  }
+
+  #[test]
+  fn lfsr_periods() {
+    assert_eq!(lfsr_period(NoiseColor::Lfsr), 32767);
+    assert_eq!(lfsr_period(NoiseColor::LfsrShort), 93);
+  }
+}
@@ -2,6 +2,8 @@
 ///
 /// Those go from C-1 to G9.
 
+use core::intrinsics::log2f32;
+
 use hertz::Hertz;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -11,6 +13,13 @@ impl Note {
   pub fn frequency(&self) -> Hertz {
     self.0
   }
+
+  /// MIDI note number, from 0 (C-1) to 127 (G9); A4 is 69.
+  ///
+  /// The number is fractional for frequencies lying between two notes.
+  pub fn number(&self) -> f32 {
+    69. + 12. * unsafe { log2f32(self.0 / 440.) }
+  }
 }
 
 pub const C_1: Note = Note(8.17580);
@@ -6,7 +6,8 @@ use core::f32::consts::PI;
 
 use hertz::Hertz;
 use sample::Sample;
-use time::SampleTime;
+use time::{SampleRate, SampleTime};
+use wavetable::WavetableWave;
 
 const TWICE_PI: f32 = 2. * PI;
 
@@ -25,7 +26,7 @@ pub fn square_wave(t: Hertz) -> Sample {
 /// The triangle wave (normalized).
 #[inline(always)]
 pub fn triangle_wave(t: Hertz) -> Sample {
-  unsafe { fabsf32((t + 1.5) % 2. - 1.) * 2. - 1. }
+  unsafe { fabsf32((2. * t + 1.5) % 2. - 1.) * 2. - 1. }
 }
 
 /// The sawtooth wave (normalized).
@@ -34,23 +35,254 @@ pub fn sawtooth_wave(t: Hertz) -> Sample {
   2. * (-(t % 1.)) + 1.
 }
 
+/// Band-limited square wave (normalized), using PolyBLEP.
+///
+/// `dt` is the phase increment between two samples (i.e. the frequency divided by the sample
+/// rate). `t` must lie in `[0; 1[`.
+#[inline(always)]
+pub fn square_wave_bandlimited(t: Hertz, dt: Hertz) -> Sample {
+  let mut half = t + 0.5;
+
+  if half >= 1. {
+    half -= 1.;
+  }
+
+  square_wave(t) + poly_blep(t, dt) - poly_blep(half, dt)
+}
+
+/// Band-limited triangle wave (normalized), using PolyBLAMP.
+///
+/// `dt` is the phase increment between two samples (i.e. the frequency divided by the sample
+/// rate). `t` must lie in `[0; 1[`.
+#[inline(always)]
+pub fn triangle_wave_bandlimited(t: Hertz, dt: Hertz) -> Sample {
+  // the slope of the triangle goes from 4 to -4 at 1/4 and from -4 to 4 at 3/4
+  let mut peak = t + 0.75;
+  let mut trough = t + 0.25;
+
+  if peak >= 1. {
+    peak -= 1.;
+  }
+
+  if trough >= 1. {
+    trough -= 1.;
+  }
+
+  triangle_wave(t) + 8. * dt * (poly_blamp(trough, dt) - poly_blamp(peak, dt))
+}
+
+/// Band-limited sawtooth wave (normalized), using PolyBLEP.
+///
+/// `dt` is the phase increment between two samples (i.e. the frequency divided by the sample
+/// rate). `t` must lie in `[0; 1[`.
+#[inline(always)]
+pub fn sawtooth_wave_bandlimited(t: Hertz, dt: Hertz) -> Sample {
+  sawtooth_wave(t) + poly_blep(t, dt)
+}
+
+/// The pulse wave (normalized), high during the `duty` first fraction of its period.
+///
+/// A duty cycle of 0.5 gives the square wave.
+#[inline(always)]
+pub fn pulse_wave(t: Hertz, duty: f32) -> Sample {
+  if t % 1. < duty { 1. } else { -1. }
+}
+
+/// Band-limited pulse wave (normalized), using PolyBLEP.
+///
+/// `dt` is the phase increment between two samples (i.e. the frequency divided by the sample
+/// rate). `t` and `duty` must lie in `[0; 1[`.
+#[inline(always)]
+pub fn pulse_wave_bandlimited(t: Hertz, dt: Hertz, duty: f32) -> Sample {
+  // branch on the same test as the naive wave so that rounding never puts both on different sides
+  // of the falling edge
+  let fall = if t < duty { t - duty + 1. } else { t - duty };
+
+  pulse_wave(t, duty) + poly_blep(t, dt) - poly_blep(fall, dt)
+}
+
+// Polynomial residual of a band-limited step going from -1 to 1 at phase 0.
+//
+// Adding it to a naive wave smooths a discontinuity of +2 over the two samples around it.
+#[inline(always)]
+fn poly_blep(t: Hertz, dt: Hertz) -> f32 {
+  if t < dt {
+    let x = t / dt;
+    x + x - x * x - 1.
+  } else if t > 1. - dt {
+    let x = (t - 1.) / dt;
+    x * x + x + x + 1.
+  } else {
+    0.
+  }
+}
+
+// Polynomial residual of a band-limited ramp (integrated PolyBLEP) for a unit change of slope
+// per sample at phase 0.
+#[inline(always)]
+fn poly_blamp(t: Hertz, dt: Hertz) -> f32 {
+  if t < dt {
+    let x = t / dt - 1.;
+    -x * x * x / 6.
+  } else if t > 1. - dt {
+    let x = (t - 1.) / dt + 1.;
+    x * x * x / 6.
+  } else {
+    0.
+  }
+}
+
+/// A normalized wave that can be sampled by an `Oscillator`.
+///
+/// The wave is sampled at a phase `t` in `[0; 1[`; `dt` is the phase increment between two samples,
+/// which band-limited waves use to know how much they must be smoothed.
+///
+/// Any `Fn(Hertz) -> Sample` – such as `sine_wave` – is a wave that ignores `dt`.
+pub trait Wave {
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample;
+
+  /// Reset the state the wave keeps between samples, such as the phases of the waves it’s made of.
+  ///
+  /// It’s called when the oscillator playing the wave is reset. By default, waves are stateless.
+  fn reset(&mut self) {}
+}
+
+impl<F> Wave for F where F: Fn(Hertz) -> Sample {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, _: Hertz) -> Sample {
+    self(t)
+  }
+}
+
+/// Waveforms available out of the box.
+///
+/// The `*BandLimited` variants suppress aliasing up to Nyquist, which the naive ones don’t.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Waveform {
+  Sine,
+  Square,
+  Triangle,
+  Sawtooth,
+  SquareBandLimited,
+  TriangleBandLimited,
+  SawtoothBandLimited,
+}
+
+impl Wave for Waveform {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    match *self {
+      Waveform::Sine => sine_wave(t),
+      Waveform::Square => square_wave(t),
+      Waveform::Triangle => triangle_wave(t),
+      Waveform::Sawtooth => sawtooth_wave(t),
+      Waveform::SquareBandLimited => square_wave_bandlimited(t, dt),
+      Waveform::TriangleBandLimited => triangle_wave_bandlimited(t, dt),
+      Waveform::SawtoothBandLimited => sawtooth_wave_bandlimited(t, dt),
+    }
+  }
+}
+
+/// Lowest duty cycle of a `Pulse`; the highest is `1 - MIN_DUTY`.
+pub const MIN_DUTY: f32 = 0.01;
+
+/// A band-limited pulse wave with a variable duty cycle.
+///
+/// The duty cycle is the fraction of the period during which the wave is high; 0.5 gives a square
+/// wave. It can be changed between samples – through `Oscillator::wave_mut` or
+/// `Oscillator::sample_pwm` – for pulse-width modulation. It’s kept within
+/// `[MIN_DUTY; 1 - MIN_DUTY]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pulse {
+  pub duty: f32
+}
+
+impl Pulse {
+  pub fn new(duty: f32) -> Self {
+    Pulse { duty }
+  }
+}
+
+impl Wave for Pulse {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    pulse_wave_bandlimited(t, dt, self.duty.max(MIN_DUTY).min(1. - MIN_DUTY))
+  }
+}
+
 /// Oscillator.
-pub struct Oscillator<F> where F: Fn(Hertz) -> Sample {
+///
+/// An oscillator can be sampled in two ways:
+///
+///   - From the absolute sample index, with `Oscillator::sample`. The phase of the wave is then
+///     recomputed from scratch for every sample, which is fine as long as the frequency never
+///     changes.
+///   - With a running phase accumulator, with `Oscillator::sample_accumulated` or
+///     `Oscillator::sample_modulated`. The phase is advanced by the current frequency at each
+///     sample and kept between calls, so that changing the frequency – pitch bend, vibrato, a new
+///     note – doesn’t make the signal jump.
+pub struct Oscillator<F> where F: Wave {
   sampling_buffer: Vec<Sample>,
   wave: F,
+  phase: f32,
+  rate: SampleRate,
+  // step between two sampling points at the oscillator’s sample rate
+  step: f32,
 }
 
-// Step between two sampling points when sampling at 44.1 kHz.
-const SAMPLING_STEP: f32 = 1. / 44100.;
-
-impl<F> Oscillator<F> where F: Fn(Hertz) -> Sample {
-  pub fn new(f: F) -> Self {
+impl<F> Oscillator<F> where F: Wave {
+  pub fn new(f: F, rate: SampleRate) -> Self {
     Oscillator {
-      sampling_buffer: Vec::with_capacity(44100),
-      wave: f
+      sampling_buffer: Vec::with_capacity(rate.0 as usize),
+      wave: f,
+      phase: 0.,
+      rate,
+      step: rate.step()
     }
   }
 
+  /// Wave the oscillator samples.
+  pub fn wave(&self) -> &F {
+    &self.wave
+  }
+
+  /// Wave the oscillator samples.
+  pub fn wave_mut(&mut self) -> &mut F {
+    &mut self.wave
+  }
+
+  /// Sample rate the oscillator was created with.
+  pub fn sample_rate(&self) -> SampleRate {
+    self.rate
+  }
+
+  /// Current phase of the oscillator, in `[0; 1[`.
+  pub fn phase(&self) -> f32 {
+    self.phase
+  }
+
+  /// Set the phase of the oscillator.
+  ///
+  /// The phase is wrapped into `[0; 1[`.
+  pub fn set_phase(&mut self, phase: f32) {
+    self.phase = wrap_phase(phase);
+  }
+
+  /// Reset the phase accumulator to 0, along with the state of the wave (see `Wave::reset`).
+  pub fn reset(&mut self) {
+    self.phase = 0.;
+    self.wave.reset();
+  }
+
+  /// Produce the next sample at the given frequency and advance the phase accumulator.
+  #[inline(always)]
+  pub fn next_sample(&mut self, freq: Hertz) -> Sample {
+    let dt = freq * self.step;
+    let signal = self.wave.sample(self.phase, dt);
+    self.phase = wrap_phase(self.phase + dt);
+    signal
+  }
+
   /// Sample from sample `start` to `end` with the given frequency.
   pub fn sample(&mut self, start: SampleTime, end: SampleTime, freq: Hertz) -> &[Sample] {
     let s = start.0;
@@ -62,9 +294,11 @@ impl<F> Oscillator<F> where F: Fn(Hertz) -> Sample {
     self.sampling_buffer.clear();
 
     // generate the samples
+    let dt = freq * self.step;
+
     for i in s..e {
-      let t = SAMPLING_STEP * i as f32;
-      let signal = (self.wave)(t * freq);
+      let t = wrap_phase(dt * i as f32);
+      let signal = self.wave.sample(t, dt);
 
       self.sampling_buffer.push(signal);
     }
@@ -72,4 +306,153 @@ impl<F> Oscillator<F> where F: Fn(Hertz) -> Sample {
     // return the samples we just generated
     &self.sampling_buffer[0 .. e - s]
   }
+
+  /// Sample from sample `start` to `end` with the given frequency, using the phase accumulator.
+  ///
+  /// Only the number of samples is taken from `start` and `end`: the phase starts where the
+  /// previous call left it.
+  pub fn sample_accumulated(&mut self, start: SampleTime, end: SampleTime, freq: Hertz) -> &[Sample] {
+    let s = start.0;
+    let e = end.0;
+
+    assert!(e >= s);
+
+    self.sampling_buffer.clear();
+
+    for _ in s..e {
+      let signal = self.next_sample(freq);
+      self.sampling_buffer.push(signal);
+    }
+
+    &self.sampling_buffer[0 .. e - s]
+  }
+
+  /// Sample as many samples as there are frequencies in `freqs`, using the phase accumulator.
+  ///
+  /// Each sample is generated with its own frequency, allowing for continuous pitch modulation.
+  pub fn sample_modulated(&mut self, freqs: &[Hertz]) -> &[Sample] {
+    self.sampling_buffer.clear();
+
+    for &freq in freqs {
+      let signal = self.next_sample(freq);
+      self.sampling_buffer.push(signal);
+    }
+
+    &self.sampling_buffer
+  }
+}
+
+impl Oscillator<Pulse> {
+  /// Sample as many samples as there are duty cycles in `duties`, using the phase accumulator.
+  ///
+  /// Each sample is generated with its own duty cycle, allowing for continuous pulse-width
+  /// modulation.
+  pub fn sample_pwm(&mut self, freq: Hertz, duties: &[f32]) -> &[Sample] {
+    self.sampling_buffer.clear();
+
+    for &duty in duties {
+      self.wave.duty = duty;
+
+      let signal = self.next_sample(freq);
+      self.sampling_buffer.push(signal);
+    }
+
+    &self.sampling_buffer
+  }
+}
+
+impl Oscillator<WavetableWave> {
+  /// Sample as many samples as there are morph positions in `morphs`, using the phase accumulator.
+  ///
+  /// Each sample is generated with its own morph position, allowing for continuous sweeps through
+  /// the wavetable.
+  pub fn sample_morphed(&mut self, freq: Hertz, morphs: &[f32]) -> &[Sample] {
+    self.sampling_buffer.clear();
+
+    for &morph in morphs {
+      self.wave.morph = morph;
+
+      let signal = self.next_sample(freq);
+      self.sampling_buffer.push(signal);
+    }
+
+    &self.sampling_buffer
+  }
+}
+
+// Wrap a phase into [0; 1[.
+#[inline(always)]
+pub(crate) fn wrap_phase(phase: f32) -> f32 {
+  phase - unsafe { floorf32(phase) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Naive triangle smoothed by the two-sample tent kernel PolyBLAMP approximates, oversampled.
+  fn smoothed_triangle(t: Hertz, dt: Hertz) -> Sample {
+    const OVERSAMPLING: usize = 1024;
+
+    let mut signal = 0.;
+    let mut weights = 0.;
+
+    for i in 0 ..= 2 * OVERSAMPLING {
+      let u = i as f32 / OVERSAMPLING as f32 - 1.;
+      let w = 1. - u.abs();
+
+      signal += w * triangle_wave(wrap_phase(t + u * dt));
+      weights += w;
+    }
+
+    signal / weights
+  }
+
+  #[test]
+  fn triangle_bandlimited_matches_smoothed_triangle() {
+    let dt = 1234.5 / 44100.;
+
+    for i in 0 .. 200 {
+      let t = wrap_phase(i as f32 * dt);
+      let expected = smoothed_triangle(t, dt);
+      let signal = triangle_wave_bandlimited(t, dt);
+
+      assert!((signal - expected).abs() < 1e-3, "phase {}: {} instead of {}", t, signal, expected);
+    }
+  }
+
+  const RATE: SampleRate = SampleRate(48000);
+
+  // A wave giving its own phase.
+  fn ramp(t: Hertz) -> Sample {
+    t
+  }
+
+  #[test]
+  fn phase_is_kept_across_calls() {
+    let mut split = Oscillator::new(ramp, RATE);
+    let mut whole = Oscillator::new(ramp, RATE);
+
+    // 375 Hz: 128 samples per period, so that phases are exact
+    let mut signal = split.sample_accumulated(SampleTime(0), SampleTime(100), 375.).to_vec();
+    signal.extend_from_slice(split.sample_accumulated(SampleTime(100), SampleTime(300), 375.));
+
+    assert_eq!(signal, whole.sample_accumulated(SampleTime(0), SampleTime(300), 375.));
+    assert_eq!(split.phase(), wrap_phase(300. / 128.));
+  }
+
+  #[test]
+  fn frequency_change_keeps_phase_continuous() {
+    let mut oscillator = Oscillator::new(ramp, RATE);
+
+    // half a period at 375 Hz, then at 750 Hz from where it left off
+    let first = oscillator.sample_accumulated(SampleTime(0), SampleTime(64), 375.).to_vec();
+    let second = oscillator.sample_accumulated(SampleTime(64), SampleTime(128), 750.).to_vec();
+
+    assert_eq!(first[63] + 1. / 128., 0.5);
+
+    for (i, &sample) in second.iter().enumerate() {
+      assert_eq!(sample, wrap_phase(0.5 + i as f32 / 64.), "sample {}", i);
+    }
+  }
 }
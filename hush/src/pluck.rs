@@ -0,0 +1,419 @@
+//! Plucked strings.
+//!
+//! `Pluck` is a physical model of a plucked string, after the Karplus–Strong algorithm: a delay line
+//! as long as the period of the note is filled with a burst of noise – the pluck – and fed back into
+//! itself through a damping filter. The burst quickly turns into a decaying, string-like tone.
+//!
+//! The length of the loop is tuned with a fractional, all-pass interpolated delay, so that high
+//! notes – whose periods are only a few samples long – stay in tune.
+
+use alloc::vec::Vec;
+use core::intrinsics::{floorf32, powf32};
+
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use noise::{Noise, NoiseColor};
+use note::Note;
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices, voice_seed};
+
+/// Lowest frequency a string can be tuned to.
+pub const MIN_FREQUENCY: Hertz = 20.;
+
+/// Default seed of the pluck noise.
+pub const DEFAULT_SEED: u32 = 0x4f1b_bcdc;
+
+// Level under which a string is considered silent.
+const SILENCE: f32 = 1e-4;
+
+// Time it takes a muted string to die out by 60 dB.
+const MUTE_DECAY: Time = 0.08;
+
+// Time it takes the level of a string to fall by 60 dB once its peaks stop.
+const LEVEL_DECAY: Time = 0.05;
+
+// Parameters shared by all voices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Settings {
+  brightness: f32,
+  decay: Time,
+  position: f32
+}
+
+/// A polyphonic plucked string instrument.
+///
+/// The sound of the string is set by:
+///
+///   - Its *brightness*, in `[0; 1]`: how much high frequencies there are in the pluck and how
+///     slowly they die out. Dull strings sound like nylon, bright ones like steel.
+///   - Its *decay*: the time it takes for the string to die out by 60 dB while the note is held.
+///     The damping filter shortens it, all the more for high notes and dull strings. Releasing a
+///     note mutes the string.
+///   - The *pluck position*, in `]0; 1[`: where the string is plucked. Plucking close to the bridge
+///     (close to 0) gives a thin sound; plucking in the middle (0.5) gives a round, hollow one.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Notes are plucked as hard as their velocity and panned by their expression. Their timbre offsets
+/// the brightness by up to 0.5 either way.
+pub struct Pluck {
+  settings: Settings,
+  rate: SampleRate,
+  seed: u32,
+  voices: Voices<PluckVoice>
+}
+
+impl Pluck {
+  pub fn new(rate: SampleRate) -> Self {
+    let settings = Settings {
+      brightness: 0.5,
+      decay: 3.,
+      position: 0.2
+    };
+
+    Pluck {
+      settings,
+      rate,
+      seed: DEFAULT_SEED,
+      voices: Voices::new(DEFAULT_VOICES, VoiceStealing::Quietest, |i| PluckVoice::new(settings, voice_seed(DEFAULT_SEED, i), rate))
+    }
+  }
+
+  /// Change the brightness (0.5 by default).
+  pub fn with_brightness(mut self, brightness: f32) -> Self {
+    self.settings.brightness = brightness.max(0.).min(1.);
+    self.update_settings();
+    self
+  }
+
+  /// Change the decay (3 seconds by default).
+  pub fn with_decay(mut self, decay: Time) -> Self {
+    self.settings.decay = decay.max(0.);
+    self.update_settings();
+    self
+  }
+
+  /// Change the pluck position (0.2 by default).
+  pub fn with_pluck_position(mut self, position: f32) -> Self {
+    self.settings.position = position.max(0.).min(1.);
+    self.update_settings();
+    self
+  }
+
+  /// Change the seed of the pluck noise.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_seed(mut self, seed: u32) -> Self {
+    let count = self.voices.len();
+
+    self.seed = seed;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy (`VoiceStealing::Quietest` by default).
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  pub fn brightness(&self) -> f32 {
+    self.settings.brightness
+  }
+
+  pub fn decay(&self) -> Time {
+    self.settings.decay
+  }
+
+  pub fn pluck_position(&self) -> f32 {
+    self.settings.position
+  }
+
+  fn update_settings(&mut self) {
+    let settings = self.settings;
+
+    for voice in self.voices.iter_mut() {
+      voice.settings = settings;
+    }
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<PluckVoice> {
+    Voices::new(count, self.voices.stealing(), |i| PluckVoice::new(self.settings, voice_seed(self.seed, i), self.rate))
+  }
+}
+
+impl Modulable for Pluck {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.voices.modulate(param, amount);
+  }
+}
+
+impl Instrument for Pluck {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+/// A voice of a `Pluck` instrument: a single string.
+pub struct PluckVoice {
+  settings: Settings,
+  noise: Noise,
+  rate: f32,
+  // delay line, large enough for the lowest frequency
+  line: Vec<Sample>,
+  write: usize,
+  freq: Hertz,
+  // brightness offset of the note, from its timbre
+  timbre: f32,
+  muted: bool,
+  // damping filter state
+  last: Sample,
+  // all-pass interpolation state
+  allpass_in: Sample,
+  allpass_out: Sample,
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  // peak follower, falling by `level_decay` at each sample
+  level: f32,
+  level_decay: f32,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+// Tuning of the loop for a given frequency.
+struct Loop {
+  // integer delay
+  delay: usize,
+  // all-pass coefficient for the fractional delay
+  allpass: f32,
+  // damping filter coefficient, which also delays the loop
+  damping: f32,
+  // gain applied at each trip around the loop
+  feedback: f32
+}
+
+impl PluckVoice {
+  fn new(settings: Settings, seed: u32, rate: SampleRate) -> Self {
+    let mut line = Vec::new();
+    line.resize((rate.0 as f32 / MIN_FREQUENCY) as usize + 2, 0.);
+
+    PluckVoice {
+      settings,
+      noise: Noise::with_seed(NoiseColor::White, seed),
+      rate: rate.0 as f32,
+      line,
+      write: 0,
+      freq: 0.,
+      timbre: 0.,
+      muted: true,
+      last: 0.,
+      allpass_in: 0.,
+      allpass_out: 0.,
+      pitch_factor: 1.,
+      gain: 1.,
+      level: 0.,
+      level_decay: unsafe { powf32(10., -3. / (LEVEL_DECAY * rate.0 as f32)) },
+      pan: (1., 1.)
+    }
+  }
+
+  fn brightness(&self) -> f32 {
+    (self.settings.brightness + 0.5 * self.timbre).max(0.).min(1.)
+  }
+
+  fn tune(&self, freq: Hertz) -> Loop {
+    let freq = freq.max(MIN_FREQUENCY);
+    let period = self.rate / freq;
+    let damping = 0.5 * (1. - self.brightness());
+
+    // the damping filter delays by `damping`; the all-pass works best with delays in [0.1; 1.1[
+    let delay = period - damping;
+    let integer = unsafe { floorf32(delay - 0.1) }.max(1.);
+    let fraction = delay - integer;
+
+    let decay = if self.muted { MUTE_DECAY } else { self.settings.decay };
+    // -60 dB after `decay` seconds, that is after `decay * freq` trips
+    let feedback = if decay > 0. { unsafe { powf32(10., -3. / (decay * freq)) } } else { 0. };
+
+    Loop {
+      delay: (integer as usize).min(self.line.len() - 1),
+      allpass: (1. - fraction) / (1. + fraction),
+      damping,
+      feedback
+    }
+  }
+}
+
+impl Voice for PluckVoice {
+  fn start(&mut self, note: Note, expression: Expression, _: SampleTime) {
+    self.freq = note.frequency();
+    self.timbre = expression.timbre.unwrap_or(0.);
+    self.pan = expression.pan_gains();
+    self.muted = false;
+
+    let tuning = self.tune(self.freq * self.pitch_factor);
+    let len = tuning.delay;
+
+    for x in &mut self.line {
+      *x = 0.;
+    }
+
+    // the pluck: a burst of noise as loud as the velocity, low-passed according to the brightness…
+    let amplitude = 0.5 * expression.velocity;
+    let smoothing = 0.1 + 0.9 * self.brightness();
+    let mut excitation = 0.;
+
+    for i in 0..len {
+      excitation += (amplitude * self.noise.next_sample() - excitation) * smoothing;
+      self.line[i] = excitation;
+    }
+
+    // …and comb-filtered according to the pluck position
+    let offset = (self.settings.position * len as f32) as usize;
+
+    if offset > 0 && offset < len {
+      for i in (offset..len).rev() {
+        self.line[i] -= self.line[i - offset];
+      }
+    }
+
+    self.write = len;
+    self.last = 0.;
+    self.allpass_in = 0.;
+    self.allpass_out = 0.;
+    self.level = 1.;
+  }
+
+  fn release(&mut self, _: SampleTime) {
+    self.muted = true;
+  }
+
+  fn is_active(&self) -> bool {
+    self.level > SILENCE
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, _: SampleTime, out: &mut [Sample]) {
+    let tuning = self.tune(self.freq * self.pitch_factor);
+    let size = self.line.len();
+
+    for sample in out.iter_mut() {
+      let read = (self.write + size - tuning.delay) % size;
+      let x = self.line[read];
+
+      // damping: one-zero low-pass
+      let damped = (1. - tuning.damping) * x + tuning.damping * self.last;
+      self.last = x;
+
+      // fractional delay: first-order all-pass
+      let y = tuning.allpass * damped + self.allpass_in - tuning.allpass * self.allpass_out;
+      self.allpass_in = damped;
+      self.allpass_out = y;
+
+      let y = y * tuning.feedback;
+
+      self.line[self.write] = y;
+      self.write = (self.write + 1) % size;
+
+      *sample += y * self.gain;
+      self.level = (self.level * self.level_decay).max(y.max(-y));
+    }
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::{A4, C7};
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  fn pluck(voice: &mut PluckVoice, note: Note) {
+    voice.start(note, Expression::default(), SampleTime(0));
+  }
+
+  #[test]
+  fn high_notes_are_in_tune() {
+    let settings = Settings { brightness: 0., decay: 10., position: 0.5 };
+    let mut voice = PluckVoice::new(settings, DEFAULT_SEED, RATE);
+    let mut out = [0.; 44100].to_vec();
+
+    pluck(&mut voice, C7);
+    voice.render(SampleTime(0), &mut out);
+
+    // the pluck leaves a DC offset, which differentiating removes
+    let slope: Vec<f32> = out.windows(2).map(|w| w[1] - w[0]).collect();
+
+    // rising zero crossings, interpolated between samples, once the pluck has settled
+    let crossings: Vec<f32> = (4410..slope.len()).filter(|&i| slope[i - 1] < 0. && slope[i] >= 0.).map(|i| {
+      (i - 1) as f32 + slope[i - 1] / (slope[i - 1] - slope[i])
+    }).collect();
+    let periods = (crossings.len() - 1) as f32;
+    let freq = periods * RATE.0 as f32 / (crossings[crossings.len() - 1] - crossings[0]);
+    let cents = 1200. * (freq / C7.frequency()).log2();
+
+    assert!(cents.abs() < 2., "{} Hz instead of {} Hz", freq, C7.frequency());
+  }
+
+  #[test]
+  fn level_follows_peaks_across_small_blocks() {
+    let settings = Settings { brightness: 0.5, decay: 3., position: 0.2 };
+    let mut voice = PluckVoice::new(settings, DEFAULT_SEED, RATE);
+
+    pluck(&mut voice, A4);
+
+    // single samples can be close to zero while the string is still ringing
+    for t in 0..4410 {
+      voice.render(SampleTime(t), &mut [0.]);
+      assert!(voice.is_active(), "silent at {}", t);
+    }
+
+    voice.release(SampleTime(4410));
+
+    let mut out = [0.; 44100].to_vec();
+    voice.render(SampleTime(4410), &mut out);
+    assert!(!voice.is_active());
+  }
+}
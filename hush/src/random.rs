@@ -0,0 +1,39 @@
+//! Deterministic pseudo-random numbers.
+
+/// A small xorshift pseudo-random generator.
+///
+/// The same seed always yields the same sequence, so that anything using it sounds the same on
+/// every run.
+#[derive(Clone, Debug)]
+pub struct Random(u32);
+
+impl Random {
+  /// Create a generator from a seed.
+  ///
+  /// A seed of 0 – which xorshift cannot work with – is replaced by a non-zero one.
+  pub fn new(seed: u32) -> Self {
+    Random(if seed == 0 { 0x9e37_79b9 } else { seed })
+  }
+
+  /// Next random 32-bit integer.
+  pub fn next_u32(&mut self) -> u32 {
+    let mut x = self.0;
+
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+
+    self.0 = x;
+    x
+  }
+
+  /// Next random number in `[0; 1[`.
+  pub fn next_unipolar(&mut self) -> f32 {
+    (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+  }
+
+  /// Next random number in `[-1; 1[`.
+  pub fn next_bipolar(&mut self) -> f32 {
+    self.next_unipolar() * 2. - 1.
+  }
+}
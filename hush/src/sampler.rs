@@ -0,0 +1,494 @@
+//! Sample playback.
+//!
+//! A `Sampler` plays recorded audio back at the pitch of the notes it’s given: the recording has a
+//! *root note* – the note it plays at its original speed – and other notes play it faster or slower.
+//!
+//! Recordings can have a *sustain loop*: while a note is held, playback loops between the loop
+//! points; once released, it carries on past the end of the loop to the end of the recording, so
+//! that the natural tail of the sound is heard.
+//!
+//! Playing a recording at another speed means reading it between its samples. How those in-between
+//! values are computed is the `Interpolation`, trading quality for speed.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use core::intrinsics::{cosf32, floorf32, sinf32};
+
+use envelope::{ADSR, Envelope};
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::Modulable;
+use note::Note;
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use voice::{Voice, VoiceStealing, Voices};
+
+/// Number of samples read on each side of the playback position by `Interpolation::Sinc`, when
+/// playing a recording at its original speed or slower.
+pub const SINC_TAPS: usize = 8;
+
+/// Most samples read on each side of the playback position by `Interpolation::Sinc`, however fast a
+/// recording is played.
+pub const MAX_SINC_TAPS: usize = 64;
+
+/// How a recording is read between its samples.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interpolation {
+  /// Nearest sample: the cheapest, with a gritty, lo-fi sound.
+  Nearest,
+  /// Straight line between the two surrounding samples.
+  Linear,
+  /// Cubic Hermite (Catmull-Rom) curve through the four surrounding samples.
+  Cubic,
+  /// Blackman-windowed sinc over `2 * SINC_TAPS` samples: the most expensive and the cleanest. When
+  /// a recording is played faster, the sinc is made narrower – and reads proportionally more
+  /// samples – so that it doesn’t alias, up to `MAX_SINC_TAPS` samples on each side: played more than
+  /// `MAX_SINC_TAPS / SINC_TAPS` times faster, it lets some aliasing through.
+  Sinc
+}
+
+// Sustain loop, in samples.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Loop {
+  start: usize,
+  end: usize
+}
+
+// The recording and how to play it; the samples are shared by all voices.
+#[derive(Clone, Debug)]
+struct Recording {
+  samples: Rc<Vec<Sample>>,
+  root: Hertz,
+  // sample rate of the recording divided by the output sample rate
+  rate_ratio: f32,
+  sustain_loop: Option<Loop>,
+  interpolation: Interpolation
+}
+
+impl Recording {
+  // Sample at index `i`, read around the playback position `index`. When looping, reading goes on
+  // from the start of the loop past its end and, once playback has entered the loop, from its end
+  // before its start. Samples out of the recording are silent.
+  #[inline(always)]
+  fn at(&self, i: isize, index: usize, looping: bool) -> Sample {
+    let i = match self.sustain_loop {
+      Some(Loop { start, end }) if looping && (i >= end as isize || index >= start && i < start as isize) => {
+        let len = (end - start) as isize;
+        start as isize + ((i - start as isize) % len + len) % len
+      }
+
+      _ => i
+    };
+
+    if i < 0 {
+      0.
+    } else {
+      self.samples.get(i as usize).cloned().unwrap_or(0.)
+    }
+  }
+
+  // Value at `index + fraction`, played at `speed` times the original speed.
+  fn interpolate(&self, index: usize, fraction: f32, speed: f32, looping: bool) -> Sample {
+    let i = index as isize;
+
+    match self.interpolation {
+      Interpolation::Nearest => {
+        if fraction < 0.5 { self.at(i, index, looping) } else { self.at(i + 1, index, looping) }
+      }
+
+      Interpolation::Linear => {
+        let a = self.at(i, index, looping);
+        let b = self.at(i + 1, index, looping);
+
+        a + (b - a) * fraction
+      }
+
+      Interpolation::Cubic => {
+        let y0 = self.at(i - 1, index, looping);
+        let y1 = self.at(i, index, looping);
+        let y2 = self.at(i + 1, index, looping);
+        let y3 = self.at(i + 2, index, looping);
+
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2. * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+        ((c3 * fraction + c2) * fraction + c1) * fraction + y1
+      }
+
+      Interpolation::Sinc => {
+        // cutoff relative to the Nyquist frequency of the recording, bounded so that no more than
+        // `MAX_SINC_TAPS` samples are read on each side
+        let cutoff = (1. / speed).min(1.).max(SINC_TAPS as f32 / MAX_SINC_TAPS as f32);
+        let half_width = SINC_TAPS as f32 / cutoff;
+        let taps = half_width as isize;
+        let mut signal = 0.;
+
+        for k in (1 - taps) ..= taps {
+          let x = k as f32 - fraction;
+          signal += self.at(i + k, index, looping) * windowed_sinc(x, cutoff, half_width);
+        }
+
+        signal
+      }
+    }
+  }
+}
+
+// Sinc lowpass kernel with a cutoff relative to Nyquist, under a Blackman window spanning
+// `[-half_width; half_width]`.
+#[inline(always)]
+fn windowed_sinc(x: f32, cutoff: f32, half_width: f32) -> f32 {
+  if x <= -half_width || x >= half_width {
+    return 0.;
+  }
+
+  let sinc = if x == 0. {
+    cutoff
+  } else {
+    unsafe { sinf32(PI * cutoff * x) / (PI * x) }
+  };
+
+  // Blackman window centered on 0
+  let w = PI * (x / half_width + 1.);
+  let window = unsafe { 0.42 - 0.5 * cosf32(w) + 0.08 * cosf32(2. * w) };
+
+  sinc * window
+}
+
+/// A polyphonic sampler.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH` and `modulation::AMPLITUDE`.
+///
+/// Notes are scaled by their velocity and panned by their expression.
+pub struct Sampler {
+  recording: Recording,
+  envelope: ADSR,
+  rate: SampleRate,
+  voices: Voices<SamplerVoice>
+}
+
+impl Sampler {
+  /// Create a sampler playing `samples` – recorded at the output sample rate `rate` – at their
+  /// original speed when playing `root`.
+  ///
+  /// The sampler has no sustain loop, uses `Interpolation::Cubic` and a short envelope that only
+  /// avoids clicks.
+  pub fn new(samples: Vec<Sample>, root: Note, rate: SampleRate) -> Self {
+    let recording = Recording {
+      samples: Rc::new(samples),
+      root: root.frequency(),
+      rate_ratio: 1.,
+      sustain_loop: None,
+      interpolation: Interpolation::Cubic
+    };
+
+    let envelope = ADSR::valid(0.002, 0.001, 1., 0.02, rate);
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |_| SamplerVoice::new(recording.clone(), &envelope));
+
+    Sampler {
+      recording,
+      envelope,
+      rate,
+      voices
+    }
+  }
+
+  /// Change the sample rate the recording was made at, if it differs from the output sample rate.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_source_rate(self, source_rate: SampleRate) -> Self {
+    let rate_ratio = source_rate.0 as f32 / self.rate.0 as f32;
+    self.with_recording(|recording| recording.rate_ratio = rate_ratio)
+  }
+
+  /// Set the sustain loop, from sample `start` included to sample `end` excluded.
+  ///
+  /// The end of the loop is clamped to the end of the recording; if the loop is then empty, the
+  /// sampler doesn’t loop.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_loop(self, start: usize, end: usize) -> Self {
+    let end = end.min(self.recording.samples.len());
+    let sustain_loop = if start < end { Some(Loop { start, end }) } else { None };
+
+    self.with_recording(|recording| recording.sustain_loop = sustain_loop)
+  }
+
+  /// Change the interpolation.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_interpolation(self, interpolation: Interpolation) -> Self {
+    self.with_recording(|recording| recording.interpolation = interpolation)
+  }
+
+  /// Change the envelope.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_envelope(mut self, envelope: ADSR) -> Self {
+    let count = self.voices.len();
+
+    self.envelope = envelope;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Recorded samples.
+  pub fn samples(&self) -> &[Sample] {
+    &self.recording.samples
+  }
+
+  /// Sustain loop, as the first and one-past-the-last samples of the loop.
+  pub fn sustain_loop(&self) -> Option<(usize, usize)> {
+    self.recording.sustain_loop.map(|Loop { start, end }| (start, end))
+  }
+
+  pub fn interpolation(&self) -> Interpolation {
+    self.recording.interpolation
+  }
+
+  // Change how the recording is played.
+  fn with_recording<F>(mut self, f: F) -> Self where F: FnOnce(&mut Recording) {
+    f(&mut self.recording);
+
+    let count = self.voices.len();
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<SamplerVoice> {
+    Voices::new(count, self.voices.stealing(), |_| SamplerVoice::new(self.recording.clone(), &self.envelope))
+  }
+}
+
+impl Modulable for Sampler {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    self.voices.modulate(param, amount);
+  }
+}
+
+impl Instrument for Sampler {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+/// A voice of a `Sampler`.
+pub struct SamplerVoice {
+  recording: Recording,
+  envelope: ADSR,
+  // playback position
+  index: usize,
+  fraction: f32,
+  // playback speed at the root note
+  speed: f32,
+  velocity: f32,
+  gate: bool,
+  // whether the end of the recording was reached
+  done: bool,
+  // modulations
+  pitch_factor: f32,
+  gain: f32,
+  level: f32,
+  buffer: Vec<Sample>,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl SamplerVoice {
+  fn new(recording: Recording, envelope: &ADSR) -> Self {
+    SamplerVoice {
+      recording,
+      envelope: envelope.clone(),
+      index: 0,
+      fraction: 0.,
+      speed: 1.,
+      velocity: 1.,
+      gate: false,
+      done: true,
+      pitch_factor: 1.,
+      gain: 1.,
+      level: 0.,
+      buffer: Vec::new(),
+      pan: (1., 1.)
+    }
+  }
+}
+
+impl Voice for SamplerVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    self.velocity = expression.velocity;
+    self.pan = expression.pan_gains();
+    self.index = 0;
+    self.fraction = 0.;
+    self.speed = note.frequency() / self.recording.root * self.recording.rate_ratio;
+    self.gate = true;
+    self.done = self.recording.samples.is_empty();
+    self.envelope.on(t);
+    self.level = self.envelope.get(t);
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+    self.envelope.off(t);
+  }
+
+  fn is_active(&self) -> bool {
+    !self.done && (self.gate || self.level > 0.)
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let end = SampleTime(start.0 + out.len());
+    let recording = &self.recording;
+    let len = recording.samples.len();
+    let speed = self.speed * self.pitch_factor;
+
+    self.buffer.clear();
+
+    for _ in 0 .. out.len() {
+      if self.done {
+        self.buffer.push(0.);
+        continue;
+      }
+
+      // the sustain loop is only played while the note is held
+      let looping = self.gate && recording.sustain_loop.is_some();
+
+      self.buffer.push(recording.interpolate(self.index, self.fraction, speed, looping));
+
+      let position = self.fraction + speed;
+      let whole = unsafe { floorf32(position) };
+
+      self.index += whole as usize;
+      self.fraction = position - whole;
+
+      match recording.sustain_loop {
+        Some(Loop { start, end }) if looping && self.index >= end => {
+          self.index = start + (self.index - end) % (end - start);
+        }
+
+        _ => ()
+      }
+
+      if self.index >= len {
+        self.done = true;
+      }
+    }
+
+    self.envelope.apply(start, &mut self.buffer);
+
+    let gain = self.gain * self.velocity;
+
+    for (sample, signal) in out.iter_mut().zip(&self.buffer) {
+      *sample += signal * gain;
+    }
+
+    self.level = self.envelope.get(end);
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn recording(samples: Vec<Sample>, interpolation: Interpolation) -> Recording {
+    Recording {
+      samples: Rc::new(samples),
+      root: 440.,
+      rate_ratio: 1.,
+      sustain_loop: Some(Loop { start: 4, end: 8 }),
+      interpolation
+    }
+  }
+
+  #[test]
+  fn reads_before_the_loop_wrap_once_playback_is_in_it() {
+    let recording = recording((0..12).map(|i| i as f32).collect(), Interpolation::Cubic);
+
+    // before the loop, reads are left alone; in the loop, they wrap on both sides
+    assert_eq!(recording.at(3, 2, true), 3.);
+    assert_eq!(recording.at(3, 4, true), 7.);
+    assert_eq!(recording.at(-2, 5, true), 6.);
+    assert_eq!(recording.at(9, 2, true), 5.);
+    assert_eq!(recording.at(9, 6, false), 9.);
+  }
+
+  #[test]
+  fn cubic_interpolation_reads_the_loop_as_periodic() {
+    // a loop preceded and followed by loud samples, and the loop repeated forever
+    let looped = recording([9., 9., 9., 9., 1., 2., 3., 4., 9., 9.].to_vec(), Interpolation::Cubic);
+    let mut periodic = recording([1., 2., 3., 4., 1., 2., 3., 4., 1., 2., 3., 4.].to_vec(), Interpolation::Cubic);
+    periodic.sustain_loop = None;
+
+    for index in 4..8 {
+      assert_eq!(looped.interpolate(index, 0.5, 1., true), periodic.interpolate(index, 0.5, 1., false));
+    }
+  }
+
+  #[test]
+  fn sinc_reads_a_bounded_number_of_samples() {
+    let mut samples = Vec::new();
+    samples.resize(1024, 1.);
+
+    let mut recording = recording(samples, Interpolation::Sinc);
+    recording.sustain_loop = None;
+
+    // played 100 times faster, the kernel would be 1600 samples wide; capped, it still passes DC
+    let signal = recording.interpolate(512, 0.25, 100., false);
+    assert!((signal - 1.).abs() < 0.05, "{}", signal);
+
+    // and it doesn’t see samples farther than `MAX_SINC_TAPS` away
+    let mut samples = Vec::new();
+    samples.resize(1024, 0.);
+    samples[512 + MAX_SINC_TAPS + 1] = 1.;
+    recording.samples = Rc::new(samples);
+    assert_eq!(recording.interpolate(512, 0.25, 100., false), 0.);
+  }
+}
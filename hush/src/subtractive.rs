@@ -0,0 +1,538 @@
+//! Subtractive synthesis.
+//!
+//! A subtractive voice starts from harmonically rich oscillators and carves the sound with a
+//! resonant filter:
+//!
+//! ```text
+//! oscillators → filter (+ filter envelope) → amplifier (+ amplitude envelope)
+//! ```
+//!
+//! Everything a voice does is described by a `Patch`, whose parameters are all public so that
+//! sounds can be designed directly in code. A few presets – bass, lead, pad – are provided as
+//! starting points.
+
+use alloc::vec::Vec;
+use core::intrinsics::exp2f32;
+
+use envelope::{ADSR, Envelope};
+use filter::{StateVariable, SvfMode};
+use hertz::Hertz;
+use instrument::{DEFAULT_VOICES, Expression, Instrument, NoteChannel};
+use modulation::{CUTOFF, Modulable, RESONANCE};
+use note::Note;
+use oscillator::Waveform;
+use sample::Sample;
+use time::{SampleRate, SampleTime, Time};
+use unison::{Unison, UnisonOscillator};
+use voice::{Voice, VoiceStealing, Voices};
+
+/// An oscillator of a `Patch`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OscillatorSettings {
+  pub waveform: Waveform,
+  /// Level in the oscillator mix.
+  pub level: f32,
+  /// Transposition, in semitones; use fractions of semitones to detune.
+  pub detune: f32
+}
+
+impl OscillatorSettings {
+  pub fn new(waveform: Waveform, level: f32, detune: f32) -> Self {
+    OscillatorSettings { waveform, level, detune }
+  }
+}
+
+/// Settings of a subtractive voice.
+#[derive(Clone, Debug)]
+pub struct Patch {
+  /// Oscillators, mixed together before the filter.
+  pub oscillators: Vec<OscillatorSettings>,
+  /// Output of the filter.
+  pub filter_mode: SvfMode,
+  /// Base cutoff frequency of the filter.
+  pub cutoff: Hertz,
+  /// Resonance of the filter, in `[0; 1[`.
+  pub resonance: f32,
+  /// How much the cutoff follows the note, from 0 (fixed cutoff) to 1 (the cutoff moves by an octave
+  /// when the note does). Notes are relative to C4.
+  pub key_tracking: f32,
+  /// Envelope of the filter cutoff.
+  pub filter_envelope: ADSR,
+  /// How much the filter envelope moves the cutoff, in octaves; may be negative.
+  pub envelope_amount: f32,
+  /// Envelope of the amplifier.
+  pub amp_envelope: ADSR,
+  /// Output gain.
+  pub gain: f32
+}
+
+impl Patch {
+  /// A patch with a single oscillator going through a neutral filter.
+  pub fn new(waveform: Waveform, rate: SampleRate) -> Self {
+    Patch {
+      oscillators: [OscillatorSettings::new(waveform, 1., 0.)].to_vec(),
+      filter_mode: SvfMode::LowPass,
+      cutoff: 20000.,
+      resonance: 0.,
+      key_tracking: 0.,
+      filter_envelope: ADSR::valid(0.001, 0.001, 0., 0.001, rate),
+      envelope_amount: 0.,
+      amp_envelope: ADSR::valid(0.005, 0.001, 1., 0.01, rate),
+      gain: 1.
+    }
+  }
+
+  /// A punchy bass: square and sawtooth an octave apart, with a snappy, resonant filter.
+  pub fn bass(rate: SampleRate) -> Self {
+    Patch {
+      oscillators: [
+        OscillatorSettings::new(Waveform::SawtoothBandLimited, 0.6, 0.),
+        OscillatorSettings::new(Waveform::SquareBandLimited, 0.4, -12.)
+      ].to_vec(),
+      filter_mode: SvfMode::LowPass,
+      cutoff: 200.,
+      resonance: 0.4,
+      key_tracking: 0.5,
+      filter_envelope: ADSR::valid(0.002, 0.2, 0.1, 0.1, rate),
+      envelope_amount: 4.,
+      amp_envelope: ADSR::valid(0.002, 0.3, 0.8, 0.08, rate),
+      gain: 0.8
+    }
+  }
+
+  /// A bright lead: two slightly detuned sawtooths.
+  pub fn lead(rate: SampleRate) -> Self {
+    Patch {
+      oscillators: [
+        OscillatorSettings::new(Waveform::SawtoothBandLimited, 0.5, -0.07),
+        OscillatorSettings::new(Waveform::SawtoothBandLimited, 0.5, 0.07)
+      ].to_vec(),
+      filter_mode: SvfMode::LowPass,
+      cutoff: 1200.,
+      resonance: 0.3,
+      key_tracking: 1.,
+      filter_envelope: ADSR::valid(0.01, 0.4, 0.4, 0.3, rate),
+      envelope_amount: 2.,
+      amp_envelope: ADSR::valid(0.01, 0.2, 0.9, 0.3, rate),
+      gain: 0.6
+    }
+  }
+
+  /// A soft pad: detuned sawtooths and a triangle, slowly opening.
+  pub fn pad(rate: SampleRate) -> Self {
+    Patch {
+      oscillators: [
+        OscillatorSettings::new(Waveform::SawtoothBandLimited, 0.35, -0.12),
+        OscillatorSettings::new(Waveform::SawtoothBandLimited, 0.35, 0.12),
+        OscillatorSettings::new(Waveform::TriangleBandLimited, 0.3, 12.)
+      ].to_vec(),
+      filter_mode: SvfMode::LowPass,
+      cutoff: 400.,
+      resonance: 0.2,
+      key_tracking: 0.5,
+      filter_envelope: ADSR::valid(1.5, 2., 0.6, 2., rate),
+      envelope_amount: 2.5,
+      amp_envelope: ADSR::valid(0.8, 1., 0.8, 2., rate),
+      gain: 0.5
+    }
+  }
+}
+
+/// A polyphonic subtractive synth.
+///
+/// Each note is played on its own voice, with its own oscillators, filter and envelopes, as
+/// described by a `Patch`.
+///
+/// It can be modulated (see `Modulable`) on `modulation::PITCH`, `modulation::AMPLITUDE`,
+/// `modulation::CUTOFF` and `modulation::RESONANCE`.
+///
+/// Each oscillator of the patch can be played as several detuned copies spread across the stereo
+/// field (see `Subtractive::with_unison`); the stereo image is available through
+/// `Instrument::get_stereo_samples`, which filters both sides.
+///
+/// Notes are scaled by their velocity and panned by their expression. Their timbre moves the cutoff
+/// by up to two octaves either way, and their pressure opens it by up to an octave.
+pub struct Subtractive {
+  patch: Patch,
+  unison: Unison,
+  rate: SampleRate,
+  voices: Voices<SubtractiveVoice>
+}
+
+impl Subtractive {
+  pub fn new(patch: Patch, rate: SampleRate) -> Self {
+    let unison = Unison::default();
+    let voices = Voices::new(DEFAULT_VOICES, VoiceStealing::Oldest, |i| SubtractiveVoice::new(&patch, unison, i, rate));
+
+    Subtractive {
+      patch,
+      unison,
+      rate,
+      voices
+    }
+  }
+
+  /// Change the number of voices.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_voices(mut self, count: usize) -> Self {
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Change the voice stealing policy.
+  pub fn with_stealing(mut self, stealing: VoiceStealing) -> Self {
+    self.voices.set_stealing(stealing);
+    self
+  }
+
+  /// Play several detuned copies of each oscillator on each voice.
+  ///
+  /// All currently playing notes are cut.
+  pub fn with_unison(mut self, unison: Unison) -> Self {
+    let count = self.voices.len();
+
+    self.unison = unison;
+    self.voices = self.make_voices(count);
+    self
+  }
+
+  /// Current patch.
+  pub fn patch(&self) -> &Patch {
+    &self.patch
+  }
+
+  /// Change the patch.
+  ///
+  /// All currently playing notes are cut.
+  pub fn set_patch(&mut self, patch: Patch) {
+    let count = self.voices.len();
+
+    self.patch = patch;
+    self.voices = self.make_voices(count);
+  }
+
+  fn make_voices(&self, count: usize) -> Voices<SubtractiveVoice> {
+    Voices::new(count, self.voices.stealing(), |i| SubtractiveVoice::new(&self.patch, self.unison, i, self.rate))
+  }
+}
+
+impl Modulable for Subtractive {
+  fn modulate(&mut self, param: &str, amount: f32) {
+    match param {
+      CUTOFF | RESONANCE => {
+        for voice in self.voices.iter_mut() {
+          voice.filter.modulate(param, amount);
+          voice.right_filter.modulate(param, amount);
+        }
+      }
+
+      _ => self.voices.modulate(param, amount)
+    }
+  }
+}
+
+impl Instrument for Subtractive {
+  fn note_on_with(&mut self, note: Note, channel: NoteChannel, expression: Expression) {
+    self.voices.note_on(note, channel, expression);
+  }
+
+  fn note_off(&mut self, channel: NoteChannel) {
+    self.voices.note_off(channel);
+  }
+
+  fn is_active(&self, _: Time) -> bool {
+    self.voices.is_active()
+  }
+
+  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    self.voices.render(start, end)
+  }
+
+  fn get_stereo_samples(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    self.voices.render_stereo(start, end, left, right);
+  }
+}
+
+/// A voice of a `Subtractive` synth.
+pub struct SubtractiveVoice {
+  // oscillators along with their frequency ratio and level
+  oscillators: Vec<(UnisonOscillator<Waveform>, f32, f32)>,
+  filter: StateVariable,
+  // filter of the right side of stereo renders, the left one going through filter
+  right_filter: StateVariable,
+  filter_envelope: ADSR,
+  amp_envelope: ADSR,
+  cutoff: Hertz,
+  key_tracking: f32,
+  envelope_amount: f32,
+  gain: f32,
+  freq: Hertz,
+  velocity: f32,
+  // cutoff of the note being played, key tracking and expression included
+  note_cutoff: Hertz,
+  // modulations
+  pitch_factor: f32,
+  gain_factor: f32,
+  gate: bool,
+  level: f32,
+  cutoffs: Vec<Hertz>,
+  buffer: Vec<Sample>,
+  // right side of stereo renders, the left one being the buffer
+  right_buffer: Vec<Sample>,
+  // amplitude envelope of stereo renders
+  levels: Vec<f32>,
+  // gains on the left and right sides
+  pan: (f32, f32)
+}
+
+impl SubtractiveVoice {
+  // Make the voice at `index`; each of its oscillators gets its own unison phases.
+  fn new(patch: &Patch, unison: Unison, index: usize, rate: SampleRate) -> Self {
+    let count = patch.oscillators.len();
+    let oscillators = patch.oscillators.iter().enumerate().map(|(i, settings)| {
+      let ratio = unsafe { exp2f32(settings.detune / 12.) };
+      (UnisonOscillator::new(settings.waveform, unison.voice(index * count + i), rate), ratio, settings.level)
+    }).collect();
+    let filter = StateVariable::new(patch.filter_mode, patch.cutoff, patch.resonance, rate);
+
+    SubtractiveVoice {
+      oscillators,
+      right_filter: filter.clone(),
+      filter,
+      filter_envelope: patch.filter_envelope.clone(),
+      amp_envelope: patch.amp_envelope.clone(),
+      cutoff: patch.cutoff,
+      key_tracking: patch.key_tracking,
+      envelope_amount: patch.envelope_amount,
+      gain: patch.gain,
+      freq: 0.,
+      velocity: 1.,
+      note_cutoff: patch.cutoff,
+      pitch_factor: 1.,
+      gain_factor: 1.,
+      gate: false,
+      level: 0.,
+      cutoffs: Vec::new(),
+      buffer: Vec::new(),
+      right_buffer: Vec::new(),
+      levels: Vec::new(),
+      pan: (1., 1.)
+    }
+  }
+
+  // Render the cutoffs of the filter, driven by the filter envelope.
+  fn render_cutoffs(&mut self, start: SampleTime, len: usize) {
+    self.cutoffs.resize(len, 0.);
+    self.filter_envelope.render(start, &mut self.cutoffs);
+
+    for cutoff in &mut self.cutoffs {
+      *cutoff = self.note_cutoff * unsafe { exp2f32(self.envelope_amount * *cutoff) };
+    }
+  }
+}
+
+impl Voice for SubtractiveVoice {
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+    // the timbre moves the cutoff by up to two octaves, the pressure opens it by up to an octave
+    let octaves = self.key_tracking * (note.number() - 60.) / 12.
+      + 2. * expression.timbre.unwrap_or(0.)
+      + expression.pressure.unwrap_or(0.);
+
+    self.freq = note.frequency();
+    self.note_cutoff = self.cutoff * unsafe { exp2f32(octaves) };
+    self.velocity = expression.velocity;
+    self.pan = expression.pan_gains();
+    self.gate = true;
+
+    for &mut (ref mut oscillator, _, _) in &mut self.oscillators {
+      oscillator.restart();
+    }
+
+    // don’t let the previous note ring through the filters
+    self.filter.reset();
+    self.right_filter.reset();
+
+    self.filter_envelope.on(t);
+    self.amp_envelope.on(t);
+    self.level = self.amp_envelope.get(t);
+  }
+
+  fn release(&mut self, t: SampleTime) {
+    self.gate = false;
+
+    self.filter_envelope.off(t);
+    self.amp_envelope.off(t);
+  }
+
+  fn is_active(&self) -> bool {
+    self.gate || self.level > 0.
+  }
+
+  fn level(&self) -> f32 {
+    self.level
+  }
+
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]) {
+    let len = out.len();
+    let end = SampleTime(start.0 + len);
+    let freq = self.freq * self.pitch_factor;
+    let gain = self.gain * self.gain_factor * self.velocity;
+
+    // oscillators
+    self.buffer.clear();
+    self.buffer.resize(len, 0.);
+
+    for &mut (ref mut oscillator, ratio, level) in &mut self.oscillators {
+      for sample in &mut self.buffer {
+        *sample += oscillator.next_sample(freq * ratio) * level;
+      }
+    }
+
+    // filter, its cutoff driven by the filter envelope
+    self.render_cutoffs(start, len);
+    self.filter.process_modulated(&mut self.buffer, &self.cutoffs);
+
+    // amplifier
+    self.amp_envelope.apply(start, &mut self.buffer);
+    self.level = self.amp_envelope.get(end);
+
+    for (sample, signal) in out.iter_mut().zip(&self.buffer) {
+      *sample += signal * gain;
+    }
+  }
+
+  fn set_pitch_factor(&mut self, factor: f32) {
+    self.pitch_factor = factor;
+  }
+
+  fn set_gain(&mut self, gain: f32) {
+    self.gain_factor = gain;
+  }
+
+  fn pan(&self) -> (f32, f32) {
+    self.pan
+  }
+
+  fn render_stereo(&mut self, start: SampleTime, left: &mut [Sample], right: &mut [Sample], _: &mut Vec<Sample>) {
+    let len = left.len();
+    let end = SampleTime(start.0 + len);
+    let freq = self.freq * self.pitch_factor;
+    let gain = self.gain * self.gain_factor * self.velocity;
+    let (left_gain, right_gain) = self.pan;
+
+    // oscillators
+    self.buffer.clear();
+    self.buffer.resize(len, 0.);
+    self.right_buffer.clear();
+    self.right_buffer.resize(len, 0.);
+
+    for &mut (ref mut oscillator, ratio, level) in &mut self.oscillators {
+      for (l, r) in self.buffer.iter_mut().zip(&mut self.right_buffer) {
+        let (left_signal, right_signal) = oscillator.next_stereo_sample(freq * ratio);
+
+        *l += left_signal * level;
+        *r += right_signal * level;
+      }
+    }
+
+    // both sides go through the same cutoffs
+    self.render_cutoffs(start, len);
+    self.filter.process_modulated(&mut self.buffer, &self.cutoffs);
+    self.right_filter.process_modulated(&mut self.right_buffer, &self.cutoffs);
+
+    // the amplitude envelope is rendered once for both sides
+    self.levels.resize(len, 0.);
+    self.amp_envelope.render(start, &mut self.levels);
+    self.level = self.amp_envelope.get(end);
+
+    for (i, level) in self.levels.iter().enumerate() {
+      left[i] += self.buffer[i] * level * gain * left_gain;
+      right[i] += self.right_buffer[i] * level * gain * right_gain;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::{A4, C4};
+  use unison::UnisonPhases;
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  fn play(subtractive: &mut Subtractive) -> (Vec<Sample>, Vec<Sample>) {
+    let mut left = [0.; 1024].to_vec();
+    let mut right = [0.; 1024].to_vec();
+
+    subtractive.note_on(A4, NoteChannel::default());
+    subtractive.get_stereo_samples(SampleTime(0), SampleTime(1024), &mut left, &mut right);
+
+    (left, right)
+  }
+
+  #[test]
+  fn stereo_render_without_unison_matches_mono_render() {
+    let mut mono = Subtractive::new(Patch::lead(RATE), RATE);
+    let mut stereo = Subtractive::new(Patch::lead(RATE), RATE);
+
+    mono.note_on(A4, NoteChannel::default());
+    let samples = mono.get_samples(SampleTime(0), SampleTime(1024)).to_vec();
+    let (left, right) = play(&mut stereo);
+
+    for ((sample, l), r) in samples.iter().zip(&left).zip(&right) {
+      assert!((sample - l).abs() < 1e-6 && (sample - r).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn unison_spreads_oscillators_across_stereo() {
+    let mut subtractive = Subtractive::new(Patch::lead(RATE), RATE).with_unison(Unison::supersaw());
+    let (left, right) = play(&mut subtractive);
+    let difference = left.iter().zip(&right).map(|(l, r)| (l - r).abs()).fold(0., f32::max);
+
+    assert!(difference > 0.01, "sides barely differ: {}", difference);
+  }
+
+  #[test]
+  fn filter_envelope_amount_moves_cutoff() {
+    for &(amount, cutoff) in &[(0., 1000.), (2., 4000.), (-1., 500.)] {
+      let mut patch = Patch::new(Waveform::Sawtooth, RATE);
+      patch.cutoff = 1000.;
+      patch.filter_envelope = ADSR::valid(0.001, 0.001, 1., 0.001, RATE);
+      patch.envelope_amount = amount;
+
+      let mut voice = SubtractiveVoice::new(&patch, Unison::default(), 0, RATE);
+      voice.start(C4, Expression::default(), SampleTime(0));
+      voice.render_cutoffs(SampleTime(0), 1024);
+
+      // once the attack is over, the envelope holds its full level
+      for &actual in &voice.cutoffs[100..] {
+        assert!((actual - cutoff).abs() < 1e-2, "amount {}: {} instead of {}", amount, actual, cutoff);
+      }
+    }
+  }
+
+  #[test]
+  fn restarted_voice_sounds_like_fresh_one() {
+    // oscillators restart at phase 0, so that only the filters could carry the previous note over
+    let patch = Patch::bass(RATE);
+    let unison = Unison::default().with_phases(UnisonPhases::Reset);
+    let mut fresh = SubtractiveVoice::new(&patch, unison, 0, RATE);
+    let mut reused = SubtractiveVoice::new(&patch, unison, 0, RATE);
+    let mut expected = [0.; 1024];
+    let mut actual = [0.; 1024];
+
+    reused.start(A4, Expression::default(), SampleTime(0));
+    reused.render(SampleTime(0), &mut [0.; 1000]);
+
+    fresh.start(A4, Expression::default(), SampleTime(1000));
+    fresh.render(SampleTime(1000), &mut expected);
+    reused.start(A4, Expression::default(), SampleTime(1000));
+    reused.render(SampleTime(1000), &mut actual);
+
+    for (i, (a, e)) in actual.iter().zip(&expected).enumerate() {
+      assert!((a - e).abs() < 1e-6, "sample {}: {} instead of {}", i, a, e);
+    }
+  }
+}
@@ -1,3 +1,7 @@
+//! Time, sample time and sample rate.
+
+use hertz::Hertz;
+
 /// Regular time.
 pub type Time = f32;
 
@@ -7,4 +11,119 @@ pub type Time = f32;
 /// samples, it will use that kind of discretized time (or indirectly). What is interesting is that
 /// a number of frames is such a time (it’s a difference of sample time), so it’s very easy to
 /// convert from that measure to an actual time that can be used to sample from.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct SampleTime(pub usize);
+
+impl SampleTime {
+  /// Convert to regular time at the given sample rate.
+  pub fn to_time(self, rate: SampleRate) -> Time {
+    self.0 as f32 / rate.0 as f32
+  }
+
+  /// Convert from regular time at the given sample rate.
+  ///
+  /// The time is rounded down to the previous sample; negative times map to the first sample.
+  pub fn from_time(t: Time, rate: SampleRate) -> Self {
+    let s = t * rate.0 as f32;
+
+    if s <= 0. {
+      SampleTime(0)
+    } else {
+      SampleTime(s as usize)
+    }
+  }
+}
+
+/// Sample rate.
+///
+/// The number of samples per second a signal is discretized with. Every component that renders
+/// samples is given its sample rate at construction time.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SampleRate(pub u32);
+
+impl SampleRate {
+  pub fn new(rate: u32) -> Self {
+    SampleRate(rate)
+  }
+
+  /// Step between two sampling points, in seconds.
+  pub fn step(&self) -> Time {
+    1. / self.0 as f32
+  }
+
+  /// Nyquist frequency (half the sample rate).
+  pub fn nyquist(&self) -> Hertz {
+    self.0 as f32 * 0.5
+  }
+}
+
+impl Default for SampleRate {
+  /// 44.1 kHz.
+  fn default() -> Self {
+    SampleRate(44100)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use envelope::{ADSR, Envelope};
+  use oscillator::Oscillator;
+
+  const RATES: [SampleRate; 2] = [SampleRate(48000), SampleRate(22050)];
+
+  // A wave giving its own phase.
+  fn ramp(t: Hertz) -> f32 {
+    t
+  }
+
+  #[test]
+  fn oscillator_periods_last_rate_over_frequency_samples() {
+    for &rate in &RATES {
+      let mut oscillator = Oscillator::new(ramp, rate);
+      let period = rate.0 as f32 / 440.;
+      let phases = oscillator.sample_accumulated(SampleTime(0), SampleTime(rate.0 as usize), 440.);
+
+      // the first period ends on the first sample past `period`
+      let first_wrap = (1..phases.len()).find(|&i| phases[i] < phases[i - 1]).unwrap();
+      assert_eq!(first_wrap, period.ceil() as usize, "rate {:?}", rate);
+
+      // a second holds 440 periods: 439 wraps, the last period ending with the second
+      let wraps = (1..phases.len()).filter(|&i| phases[i] < phases[i - 1]).count();
+      assert_eq!(wraps, 439, "rate {:?}", rate);
+      assert!(oscillator.phase() < 1e-2 || oscillator.phase() > 1. - 1e-2, "rate {:?}", rate);
+    }
+  }
+
+  #[test]
+  fn adsr_times_are_in_seconds() {
+    for &rate in &RATES {
+      let samples = |t: Time| (t * rate.0 as f32) as usize;
+      let (a, d, r) = (samples(0.02), samples(0.04), samples(0.1));
+      let mut envelope = ADSR::new(0.02, 0.04, 0.5, 0.1, rate).unwrap();
+      let off = 10000;
+
+      envelope.on(SampleTime(0));
+      envelope.off(SampleTime(off));
+
+      // a third of each segment, then its end
+      let expected = [
+        (a / 3, 1. / 3.),
+        (a, 1.),
+        (a + d / 3, 1. - 0.5 / 3.),
+        (a + d, 0.5),
+        (off, 0.5),
+        (off + r / 3, 0.5 - 0.5 / 3.),
+        (off + r, 0.)
+      ];
+
+      for &(t, level) in &expected {
+        let actual = envelope.get(SampleTime(t));
+        assert!((actual - level).abs() < 1e-3, "rate {:?}, sample {}: {} instead of {}", rate, t, actual, level);
+      }
+
+      assert!(envelope.is_active(SampleTime(off + r - 1)));
+      assert!(!envelope.is_active(SampleTime(off + r)));
+    }
+  }
+}
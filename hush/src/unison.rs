@@ -0,0 +1,285 @@
+//! Unison.
+//!
+//! Unison stacks several detuned copies of an oscillator and spreads them across the stereo field.
+//! The copies beat against each other, which thickens the sound – seven sawtooths give the famous
+//! *supersaw*.
+//!
+//! Each copy has its own phase, which can be reset, evenly spread or randomized whenever a note
+//! starts.
+
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use core::intrinsics::{cosf32, exp2f32, sinf32, sqrtf32};
+
+use hertz::Hertz;
+use oscillator::{Oscillator, Wave};
+use random::Random;
+use sample::Sample;
+use time::SampleRate;
+use voice::voice_seed;
+
+/// Default seed of random unison phases.
+pub const DEFAULT_SEED: u32 = 0x1b87_3593;
+
+/// What the phases of the copies are when a note starts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnisonPhases {
+  /// Phases keep running from note to note.
+  Free,
+  /// All copies restart at phase 0: every note has the same, punchy attack.
+  Reset,
+  /// Copies restart at evenly spread phases.
+  Spread,
+  /// Copies restart at random phases, drawn from a seeded generator. Instruments derive a different
+  /// seed for each of their voices and oscillators.
+  Random(u32)
+}
+
+/// Unison settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unison {
+  /// Number of copies (at least 1).
+  pub voices: usize,
+  /// Detune between the lowest and highest copies, in cents.
+  pub detune: f32,
+  pub phases: UnisonPhases,
+  /// Stereo spread, from 0 (all copies centered) to 1 (copies spread from left to right).
+  pub stereo: f32
+}
+
+impl Unison {
+  /// Unison with `voices` copies spread over `detune` cents, random phases and full stereo spread.
+  pub fn new(voices: usize, detune: f32) -> Self {
+    Unison {
+      voices: voices.max(1),
+      detune,
+      phases: UnisonPhases::Random(DEFAULT_SEED),
+      stereo: 1.
+    }
+  }
+
+  /// The classic supersaw spread: seven copies over 50 cents.
+  pub fn supersaw() -> Self {
+    Self::new(7, 50.)
+  }
+
+  pub fn with_phases(self, phases: UnisonPhases) -> Self {
+    Unison { phases, ..self }
+  }
+
+  pub fn with_stereo(self, stereo: f32) -> Self {
+    Unison { stereo, ..self }
+  }
+
+  // Unison of the voice at `index`; random phases are given different seeds.
+  pub(crate) fn voice(self, index: usize) -> Self {
+    match self.phases {
+      UnisonPhases::Random(seed) => self.with_phases(UnisonPhases::Random(voice_seed(seed, index))),
+      _ => self
+    }
+  }
+}
+
+impl Default for Unison {
+  /// A single copy with a free-running phase: no unison at all.
+  fn default() -> Self {
+    Unison {
+      voices: 1,
+      detune: 0.,
+      phases: UnisonPhases::Free,
+      stereo: 0.
+    }
+  }
+}
+
+// A copy of the oscillator.
+struct Detuned<F> where F: Wave {
+  oscillator: Oscillator<F>,
+  ratio: f32,
+  left: f32,
+  right: f32
+}
+
+/// A stack of detuned oscillators.
+///
+/// The copies are mixed with a gain of `1 / sqrt(voices)`, which keeps the loudness about the same
+/// whatever the number of copies.
+pub struct UnisonOscillator<F> where F: Wave {
+  copies: Vec<Detuned<F>>,
+  phases: UnisonPhases,
+  random: Random,
+  gain: f32
+}
+
+impl<F> UnisonOscillator<F> where F: Wave + Clone {
+  pub fn new(wave: F, unison: Unison, rate: SampleRate) -> Self {
+    let count = unison.voices.max(1);
+
+    // position of the copy at `i` in [-1; 1]
+    let position = |i: usize| if count == 1 { 0. } else { 2. * i as f32 / (count - 1) as f32 - 1. };
+
+    let copies = (0..count).map(|i| {
+      let ratio = unsafe { exp2f32(position(i) * unison.detune * 0.5 / 1200.) };
+      // take positions alternately from the left and right ends, so that neighbouring detunes don’t
+      // end up on the same side
+      let side = if i % 2 == 0 { i / 2 } else { count - 1 - i / 2 };
+      let pan = position(side) * unison.stereo;
+      // constant power, unity gain at the center
+      let angle = (pan + 1.) * PI * 0.25;
+      let (left, right) = unsafe { (cosf32(angle) * sqrtf32(2.), sinf32(angle) * sqrtf32(2.)) };
+
+      Detuned {
+        oscillator: Oscillator::new(wave.clone(), rate),
+        ratio,
+        left,
+        right
+      }
+    }).collect();
+
+    let seed = match unison.phases {
+      UnisonPhases::Random(seed) => seed,
+      _ => DEFAULT_SEED
+    };
+
+    UnisonOscillator {
+      copies,
+      phases: unison.phases,
+      random: Random::new(seed),
+      gain: 1. / unsafe { sqrtf32(count as f32) }
+    }
+  }
+}
+
+impl<F> UnisonOscillator<F> where F: Wave {
+  /// Number of copies.
+  pub fn len(&self) -> usize {
+    self.copies.len()
+  }
+
+  /// Waves of all copies.
+  pub fn waves_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut F> + 'a {
+    self.copies.iter_mut().map(|copy| copy.oscillator.wave_mut())
+  }
+
+  /// Set the phases of the copies for a new note.
+  pub fn restart(&mut self) {
+    let count = self.copies.len() as f32;
+
+    for (i, copy) in self.copies.iter_mut().enumerate() {
+      let phase = match self.phases {
+        UnisonPhases::Free => continue,
+        UnisonPhases::Reset => 0.,
+        UnisonPhases::Spread => i as f32 / count,
+        UnisonPhases::Random(_) => self.random.next_unipolar()
+      };
+
+      // the state of the wave restarts along with its phase
+      copy.oscillator.reset();
+      copy.oscillator.set_phase(phase);
+    }
+  }
+
+  /// Produce the next mono sample at the given frequency.
+  #[inline(always)]
+  pub fn next_sample(&mut self, freq: Hertz) -> Sample {
+    let mut signal = 0.;
+
+    for copy in &mut self.copies {
+      signal += copy.oscillator.next_sample(freq * copy.ratio);
+    }
+
+    signal * self.gain
+  }
+
+  /// Produce the next stereo sample – left and right – at the given frequency.
+  #[inline(always)]
+  pub fn next_stereo_sample(&mut self, freq: Hertz) -> (Sample, Sample) {
+    let mut left = 0.;
+    let mut right = 0.;
+
+    for copy in &mut self.copies {
+      let signal = copy.oscillator.next_sample(freq * copy.ratio);
+
+      left += signal * copy.left;
+      right += signal * copy.right;
+    }
+
+    (left * self.gain, right * self.gain)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::vec::Vec;
+  use oscillator::Waveform;
+
+  const RATE: SampleRate = SampleRate(44100);
+
+  fn oscillator(unison: Unison) -> UnisonOscillator<Waveform> {
+    UnisonOscillator::new(Waveform::Sawtooth, unison, RATE)
+  }
+
+  fn phases(oscillator: &UnisonOscillator<Waveform>) -> Vec<f32> {
+    oscillator.copies.iter().map(|copy| copy.oscillator.phase()).collect()
+  }
+
+  #[test]
+  fn copies_are_detuned_evenly_over_the_spread() {
+    let unison = oscillator(Unison::new(5, 40.));
+    let cents: Vec<f32> = unison.copies.iter().map(|copy| 1200. * copy.ratio.log2()).collect();
+
+    for (i, cents) in cents.iter().enumerate() {
+      assert!((cents - (10. * i as f32 - 20.)).abs() < 1e-3, "{:?}", cents);
+    }
+  }
+
+  #[test]
+  fn copies_are_spread_across_stereo() {
+    for count in 2..9 {
+      let unison = oscillator(Unison::new(count, 20.));
+      let mut pans: Vec<f32> = unison.copies.iter().map(|copy| {
+        // constant power
+        assert!((copy.left * copy.left + copy.right * copy.right - 2.).abs() < 1e-5);
+        copy.right.atan2(copy.left) * 4. / PI - 1.
+      }).collect();
+
+      // every position from left to right is taken once
+      pans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+      for (i, pan) in pans.iter().enumerate() {
+        let expected = 2. * i as f32 / (count - 1) as f32 - 1.;
+        assert!((pan - expected).abs() < 1e-3, "{} copies: {:?}", count, pans);
+      }
+    }
+  }
+
+  #[test]
+  fn copies_are_centered_without_stereo() {
+    let unison = oscillator(Unison::supersaw().with_stereo(0.));
+
+    for copy in &unison.copies {
+      assert!((copy.left - 1.).abs() < 1e-6 && (copy.right - 1.).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  fn random_phases_differ_across_copies_and_voices() {
+    let unison = Unison::supersaw();
+    let mut first = oscillator(unison.voice(0));
+    let mut second = oscillator(unison.voice(1));
+
+    first.restart();
+    second.restart();
+
+    let first_phases = phases(&first);
+
+    for (i, a) in first_phases.iter().enumerate() {
+      for b in &first_phases[i + 1..] {
+        assert!(a != b, "{:?}", first_phases);
+      }
+    }
+
+    assert_ne!(first_phases, phases(&second));
+  }
+}
@@ -0,0 +1,464 @@
+//! Voices and voice allocation.
+//!
+//! A polyphonic instrument plays each of its notes on a *voice*. Voices are allocated when a note
+//! is pressed on a `NoteChannel` and given back once they’re done sounding. When all voices are
+//! busy, a voice is stolen according to a `VoiceStealing` policy.
+
+use alloc::vec::Vec;
+use core::intrinsics::exp2f32;
+
+use instrument::{Expression, NoteChannel};
+use modulation::{AMPLITUDE, Modulable, PITCH};
+use note::Note;
+use sample::Sample;
+use time::SampleTime;
+
+/// Policy used to choose which voice to steal when no voice is free.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VoiceStealing {
+  /// Steal the voice that was started first.
+  Oldest,
+  /// Steal the voice with the lowest current level.
+  Quietest,
+  /// Steal a voice already playing the same note, or the oldest one if there’s none.
+  SameNote,
+}
+
+/// A single voice of a polyphonic instrument.
+pub trait Voice {
+  /// Start playing a note with an expression at the given sample time.
+  fn start(&mut self, note: Note, expression: Expression, t: SampleTime);
+
+  /// Release the note being played at the given sample time.
+  fn release(&mut self, t: SampleTime);
+
+  /// Is the voice still producing sound?
+  fn is_active(&self) -> bool;
+
+  /// Current level of the voice, used to find the quietest voice.
+  fn level(&self) -> f32;
+
+  /// Render `out.len()` samples starting at `start` and add them to `out`.
+  fn render(&mut self, start: SampleTime, out: &mut [Sample]);
+
+  /// Set the factor the frequency of the note is multiplied by, for pitch modulation.
+  ///
+  /// By default, voices ignore pitch modulation.
+  fn set_pitch_factor(&mut self, _: f32) {}
+
+  /// Set the gain the voice is multiplied by, for amplitude modulation.
+  ///
+  /// By default, voices ignore amplitude modulation.
+  fn set_gain(&mut self, _: f32) {}
+
+  /// Gains of the note being played on the left and right sides (see `Expression::pan_gains`).
+  ///
+  /// By default, voices are centered.
+  fn pan(&self) -> (f32, f32) {
+    (1., 1.)
+  }
+
+  /// Render `left.len()` stereo samples starting at `start` and add them to `left` and `right`.
+  ///
+  /// `buffer` can be used freely as scratch space. By default, the voice is rendered in mono into it
+  /// and panned with `Voice::pan`.
+  fn render_stereo(&mut self, start: SampleTime, left: &mut [Sample], right: &mut [Sample], buffer: &mut Vec<Sample>) {
+    let (left_gain, right_gain) = self.pan();
+
+    buffer.clear();
+    buffer.resize(left.len(), 0.);
+    self.render(start, buffer);
+
+    for ((l, r), signal) in left.iter_mut().zip(right.iter_mut()).zip(buffer.iter()) {
+      *l += signal * left_gain;
+      *r += signal * right_gain;
+    }
+  }
+}
+
+// A voice along with its allocation state.
+struct Slot<V> {
+  voice: V,
+  note: Option<Note>,
+  // channel holding the voice; None once released
+  channel: Option<NoteChannel>,
+  // allocation order, used to find the oldest voice
+  stamp: u64,
+}
+
+/// A fixed set of voices, allocated on note channels.
+pub struct Voices<V> {
+  slots: Vec<Slot<V>>,
+  stealing: VoiceStealing,
+  stamp: u64,
+  // sample time at which the next block starts; events without a time are applied at that time
+  now: SampleTime,
+  mixing_buffer: Vec<Sample>,
+}
+
+impl<V> Voices<V> where V: Voice {
+  /// Create `count` voices with `make`, which is given the index of each voice – e.g. to seed them
+  /// differently.
+  ///
+  /// `count` is at least 1.
+  pub fn new<F>(count: usize, stealing: VoiceStealing, mut make: F) -> Self where F: FnMut(usize) -> V {
+    let slots = (0..count.max(1)).map(|i| Slot {
+      voice: make(i),
+      note: None,
+      channel: None,
+      stamp: 0
+    }).collect();
+
+    Voices {
+      slots,
+      stealing,
+      stamp: 0,
+      now: SampleTime(0),
+      mixing_buffer: Vec::new()
+    }
+  }
+
+  /// Number of voices.
+  pub fn len(&self) -> usize {
+    self.slots.len()
+  }
+
+  /// Voice stealing policy.
+  pub fn stealing(&self) -> VoiceStealing {
+    self.stealing
+  }
+
+  /// Change the voice stealing policy; playing notes aren’t affected.
+  pub fn set_stealing(&mut self, stealing: VoiceStealing) {
+    self.stealing = stealing;
+  }
+
+  /// Sample time at which the next rendered block starts.
+  pub fn now(&self) -> SampleTime {
+    self.now
+  }
+
+  /// Iterate over all voices, active or not.
+  pub fn iter_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut V> + 'a {
+    self.slots.iter_mut().map(|slot| &mut slot.voice)
+  }
+
+  /// Start a note with an expression on a channel, at the start of the next block.
+  ///
+  /// If the channel is already holding a voice, that voice is restarted with the new note.
+  /// Otherwise, a free voice is used, or one is stolen.
+  pub fn note_on(&mut self, note: Note, channel: NoteChannel, expression: Expression) -> &mut V {
+    let now = self.now;
+    self.note_on_at(note, channel, expression, now)
+  }
+
+  /// Start a note with an expression on a channel, at sample time `t`.
+  ///
+  /// `t` should be within the next block, so that the note starts in the middle of it rather than
+  /// at its start.
+  pub fn note_on_at(&mut self, note: Note, channel: NoteChannel, expression: Expression, t: SampleTime) -> &mut V {
+    let index = self.allocate(note, channel);
+    let slot = &mut self.slots[index];
+
+    self.stamp += 1;
+    slot.note = Some(note);
+    slot.channel = Some(channel);
+    slot.stamp = self.stamp;
+    slot.voice.start(note, expression, t);
+
+    &mut slot.voice
+  }
+
+  /// Release the voice held by a channel, if any, at the start of the next block.
+  pub fn note_off(&mut self, channel: NoteChannel) {
+    let now = self.now;
+    self.note_off_at(channel, now);
+  }
+
+  /// Release the voice held by a channel, if any, at sample time `t`; see `note_on_at`.
+  pub fn note_off_at(&mut self, channel: NoteChannel, t: SampleTime) {
+    for slot in self.slots.iter_mut().filter(|slot| slot.channel == Some(channel)) {
+      slot.channel = None;
+      slot.voice.release(t);
+    }
+  }
+
+  /// Is any voice active?
+  pub fn is_active(&self) -> bool {
+    self.slots.iter().any(|slot| slot.voice.is_active())
+  }
+
+  /// Render and mix all active voices from `start` to `end`.
+  pub fn render(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
+    assert!(end >= start);
+
+    let len = end.0 - start.0;
+
+    self.mixing_buffer.clear();
+    self.mixing_buffer.resize(len, 0.);
+
+    for slot in &mut self.slots {
+      if slot.voice.is_active() {
+        slot.voice.render(start, &mut self.mixing_buffer);
+      } else {
+        slot.note = None;
+      }
+    }
+
+    self.now = end;
+
+    &self.mixing_buffer
+  }
+
+  /// Render and mix all active voices from `start` to `end` in stereo, into `left` and `right`.
+  ///
+  /// Both slices are expected to be `end - start` samples long.
+  pub fn render_stereo(&mut self, start: SampleTime, end: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    assert!(end >= start);
+    assert_eq!(left.len(), end.0 - start.0);
+
+    for x in left.iter_mut().chain(right.iter_mut()) {
+      *x = 0.;
+    }
+
+    self.mix_stereo(start, left, right);
+  }
+
+  /// Render all active voices from `start` on in stereo and add them to `left` and `right`.
+  pub fn mix_stereo(&mut self, start: SampleTime, left: &mut [Sample], right: &mut [Sample]) {
+    assert_eq!(left.len(), right.len());
+
+    for slot in &mut self.slots {
+      if slot.voice.is_active() {
+        slot.voice.render_stereo(start, left, right, &mut self.mixing_buffer);
+      } else {
+        slot.note = None;
+      }
+    }
+
+    self.now = SampleTime(start.0 + left.len());
+  }
+
+  // Find the voice to play a note on.
+  fn allocate(&self, note: Note, channel: NoteChannel) -> usize {
+    // the channel is already held
+    if let Some(i) = self.slots.iter().position(|slot| slot.channel == Some(channel)) {
+      return i;
+    }
+
+    // a voice is free
+    if let Some(i) = self.slots.iter().position(|slot| slot.channel.is_none() && !slot.voice.is_active()) {
+      return i;
+    }
+
+    // steal a voice; released voices still ringing are stolen first
+    let candidates = || {
+      let released = self.slots.iter().any(|slot| slot.channel.is_none());
+      self.slots.iter().enumerate().filter(move |&(_, slot)| !released || slot.channel.is_none())
+    };
+
+    let oldest = || candidates().min_by_key(|&(_, slot)| slot.stamp).map(|(i, _)| i).unwrap_or(0);
+
+    match self.stealing {
+      VoiceStealing::Oldest => oldest(),
+
+      VoiceStealing::Quietest => {
+        candidates().fold(None, |quietest: Option<(usize, f32)>, (i, slot)| {
+          let level = slot.voice.level();
+
+          match quietest {
+            Some((_, l)) if l <= level => quietest,
+            _ => Some((i, level))
+          }
+        }).map(|(i, _)| i).unwrap_or(0)
+      }
+
+      VoiceStealing::SameNote => {
+        self.slots.iter().position(|slot| slot.note == Some(note)).unwrap_or_else(oldest)
+      }
+    }
+  }
+}
+
+// Seed of the voice at `index` among voices seeded with `seed`, so that they don’t all sound the same.
+#[inline(always)]
+pub(crate) fn voice_seed(seed: u32, index: usize) -> u32 {
+  seed.wrapping_add(index as u32 + 1)
+}
+
+impl<V> Modulable for Voices<V> where V: Voice {
+  /// All voices are modulated on `modulation::PITCH` and `modulation::AMPLITUDE`, through
+  /// `Voice::set_pitch_factor` and `Voice::set_gain`; other parameters are ignored.
+  fn modulate(&mut self, param: &str, amount: f32) {
+    match param {
+      PITCH => {
+        let factor = unsafe { exp2f32(amount / 12.) };
+
+        for slot in &mut self.slots {
+          slot.voice.set_pitch_factor(factor);
+        }
+      }
+
+      AMPLITUDE => {
+        let gain = (1. + amount).max(0.);
+
+        for slot in &mut self.slots {
+          slot.voice.set_gain(gain);
+        }
+      }
+
+      _ => ()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use note::{A4, C4, E4};
+
+  // A voice that rings until it’s restarted, at a constant level set by the velocity of its note.
+  struct TestVoice {
+    note: Option<Note>,
+    level: f32,
+    pan: (f32, f32),
+    released: bool,
+    // time of the last start or release
+    time: SampleTime
+  }
+
+  impl Voice for TestVoice {
+    fn start(&mut self, note: Note, expression: Expression, t: SampleTime) {
+      self.note = Some(note);
+      self.time = t;
+      self.level = expression.velocity;
+      self.pan = expression.pan_gains();
+      self.released = false;
+    }
+
+    fn release(&mut self, t: SampleTime) {
+      self.released = true;
+      self.time = t;
+    }
+
+    fn is_active(&self) -> bool {
+      self.note.is_some()
+    }
+
+    fn level(&self) -> f32 {
+      self.level
+    }
+
+    fn render(&mut self, _: SampleTime, out: &mut [Sample]) {
+      for sample in out {
+        *sample += self.level;
+      }
+    }
+
+    fn pan(&self) -> (f32, f32) {
+      self.pan
+    }
+  }
+
+  fn voices(count: usize, stealing: VoiceStealing) -> Voices<TestVoice> {
+    Voices::new(count, stealing, |_| TestVoice { note: None, level: 0., pan: (1., 1.), released: false, time: SampleTime(0) })
+  }
+
+  fn notes(voices: &mut Voices<TestVoice>) -> Vec<Option<Note>> {
+    voices.iter_mut().map(|voice| voice.note).collect()
+  }
+
+  #[test]
+  fn oldest_steals_first_started_voice() {
+    let mut voices = voices(2, VoiceStealing::Oldest);
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::default());
+    voices.note_on(E4, NoteChannel::new(1), Expression::default());
+    voices.note_on(A4, NoteChannel::new(2), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(A4), Some(E4)]);
+  }
+
+  #[test]
+  fn quietest_steals_lowest_level() {
+    let mut voices = voices(3, VoiceStealing::Quietest);
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::new(0.8));
+    voices.note_on(E4, NoteChannel::new(1), Expression::new(0.2));
+    voices.note_on(C4, NoteChannel::new(2), Expression::new(0.5));
+    voices.note_on(A4, NoteChannel::new(3), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(C4), Some(A4), Some(C4)]);
+  }
+
+  #[test]
+  fn same_note_steals_voice_playing_note() {
+    let mut voices = voices(2, VoiceStealing::SameNote);
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::default());
+    voices.note_on(A4, NoteChannel::new(1), Expression::default());
+    voices.note_on(A4, NoteChannel::new(2), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(C4), Some(A4)]);
+
+    // no voice plays the note: the oldest one is stolen
+    voices.note_on(E4, NoteChannel::new(3), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(E4), Some(A4)]);
+  }
+
+  #[test]
+  fn released_voices_are_stolen_first() {
+    let mut voices = voices(2, VoiceStealing::Oldest);
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::default());
+    voices.note_on(E4, NoteChannel::new(1), Expression::default());
+    voices.note_off(NoteChannel::new(1));
+    voices.note_on(A4, NoteChannel::new(2), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(C4), Some(A4)]);
+  }
+
+  #[test]
+  fn note_off_unknown_channel_releases_nothing() {
+    let mut voices = voices(2, VoiceStealing::Oldest);
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::default());
+    voices.note_off(NoteChannel::new(1));
+
+    assert!(voices.iter_mut().all(|voice| !voice.released));
+
+    // the channel is still held: playing on it again restarts its voice
+    voices.note_on(E4, NoteChannel::new(0), Expression::default());
+
+    assert_eq!(notes(&mut voices), [Some(E4), None]);
+  }
+
+  #[test]
+  fn render_stereo_pans_voices() {
+    let mut voices = voices(2, VoiceStealing::Oldest);
+    let mut left = [1.; 4];
+    let mut right = [1.; 4];
+
+    voices.note_on(C4, NoteChannel::new(0), Expression::new(0.5).with_pan(-1.));
+    voices.note_on(E4, NoteChannel::new(1), Expression::new(0.25));
+    voices.render_stereo(SampleTime(0), SampleTime(4), &mut left, &mut right);
+
+    assert_eq!(left, [0.75; 4]);
+    assert_eq!(right, [0.25; 4]);
+    assert_eq!(voices.now(), SampleTime(4));
+  }
+
+  #[test]
+  fn events_are_applied_at_their_time() {
+    let mut voices = voices(1, VoiceStealing::Oldest);
+    let channel = NoteChannel::new(0);
+
+    voices.render(SampleTime(0), SampleTime(64));
+    assert_eq!(voices.note_on(C4, channel, Expression::default()).time, SampleTime(64));
+    assert_eq!(voices.note_on_at(E4, channel, Expression::default(), SampleTime(100)).time, SampleTime(100));
+
+    voices.note_off_at(channel, SampleTime(120));
+    assert!(voices.iter_mut().all(|voice| voice.released && voice.time == SampleTime(120)));
+  }
+}
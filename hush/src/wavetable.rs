@@ -0,0 +1,306 @@
+//! Wavetables.
+//!
+//! A wavetable is a stack of *frames*, each frame being a single cycle of a waveform. A wavetable
+//! oscillator plays one frame at a time, with a *morph* position crossfading smoothly between
+//! neighbouring frames.
+//!
+//! Frames can be loaded from single-cycle waveforms or generated procedurally. They’re mip-mapped
+//! per octave when the table is built: each level keeps half the harmonics of the previous one, and
+//! the oscillator picks the level whose harmonics all stay below Nyquist, so that wavetables don’t
+//! alias whatever the pitch.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+use core::intrinsics::{cosf32, floorf32, log2f32, sinf32};
+
+use hertz::Hertz;
+use oscillator::Wave;
+use sample::Sample;
+
+/// Mip-mapped frames of a single-cycle waveform.
+#[derive(Clone, Debug)]
+pub struct Wavetable {
+  size: usize,
+  // levels of each frame; level k holds (size / 2) >> k harmonics at most
+  frames: Vec<Vec<Vec<Sample>>>
+}
+
+impl Wavetable {
+  /// Build a wavetable from single-cycle frames.
+  ///
+  /// All frames must have the same size, which must be a power of two of at least 4. `None` is
+  /// returned otherwise, or if there’s no frame.
+  pub fn new(frames: &[&[Sample]]) -> Option<Self> {
+    let size = frames.first()?.len();
+
+    if size < 4 || !size.is_power_of_two() || frames.iter().any(|frame| frame.len() != size) {
+      return None;
+    }
+
+    let frames = frames.iter().map(|frame| mipmaps(frame)).collect();
+
+    Some(Wavetable { size, frames })
+  }
+
+  /// Build a wavetable procedurally.
+  ///
+  /// `f` is called with the index of a frame and a normalized phase in `[0; 1[` for each of the
+  /// `size` samples of the `frames` frames. `size` must be a power of two of at least 4.
+  pub fn from_fn<F>(size: usize, frames: usize, mut f: F) -> Option<Self> where F: FnMut(usize, f32) -> Sample {
+    if size < 4 || !size.is_power_of_two() || frames == 0 {
+      return None;
+    }
+
+    let mut frame = Vec::with_capacity(size);
+    let frames = (0..frames).map(|i| {
+      frame.clear();
+      frame.extend((0..size).map(|j| f(i, j as f32 / size as f32)));
+      mipmaps(&frame)
+    }).collect();
+
+    Some(Wavetable { size, frames })
+  }
+
+  /// Number of samples of a frame.
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  /// Number of frames.
+  pub fn frame_count(&self) -> usize {
+    self.frames.len()
+  }
+
+  /// Sample the table at phase `t` (in `[0; 1[`) and morph position `morph` (in `[0; 1]`, going
+  /// from the first frame to the last).
+  ///
+  /// `dt` is the phase increment between two samples and selects the mip-map level.
+  pub fn sample(&self, t: Hertz, dt: Hertz, morph: f32) -> Sample {
+    let level = self.level(dt);
+    let position = morph.clamp(0., 1.) * (self.frames.len() - 1) as f32;
+    let index = position as usize;
+    let frac = position - index as f32;
+
+    let a = lookup(&self.frames[index][level], t);
+
+    if frac > 0. {
+      let b = lookup(&self.frames[index + 1][level], t);
+      a + (b - a) * frac
+    } else {
+      a
+    }
+  }
+
+  // Highest mip-map level whose harmonics all stay below Nyquist.
+  fn level(&self, dt: Hertz) -> usize {
+    let levels = self.frames[0].len();
+    let x = self.size as f32 * dt;
+
+    if x < 1. {
+      0
+    } else {
+      ((unsafe { floorf32(log2f32(x)) } as usize) + 1).min(levels - 1)
+    }
+  }
+}
+
+// Interpolated lookup at a normalized phase.
+#[inline(always)]
+fn lookup(table: &[Sample], t: Hertz) -> Sample {
+  let size = table.len();
+  let x = t * size as f32;
+  let i = x as usize & (size - 1);
+  let frac = x - unsafe { floorf32(x) };
+  let a = table[i];
+  let b = table[(i + 1) & (size - 1)];
+
+  a + (b - a) * frac
+}
+
+// Build the mip-map levels of a frame by removing harmonics from its spectrum.
+fn mipmaps(frame: &[Sample]) -> Vec<Vec<Sample>> {
+  let size = frame.len();
+  let mut re = frame.to_vec();
+  let mut im = Vec::new();
+  im.resize(size, 0.);
+
+  fft(&mut re, &mut im);
+
+  let mut levels = Vec::new();
+  let mut harmonics = size / 2;
+
+  while harmonics >= 1 {
+    // keep the DC and the harmonics below Nyquist, along with their mirrors
+    let mut level_re = re.clone();
+    let mut level_im = im.clone();
+    let kept = harmonics.min(size / 2 - 1);
+
+    for k in kept + 1 .. size - kept {
+      level_re[k] = 0.;
+      level_im[k] = 0.;
+    }
+
+    // inverse transform through conjugation
+    for x in &mut level_im {
+      *x = -*x;
+    }
+
+    fft(&mut level_re, &mut level_im);
+
+    for x in &mut level_re {
+      *x /= size as f32;
+    }
+
+    levels.push(level_re);
+    harmonics /= 2;
+  }
+
+  levels
+}
+
+// In-place radix-2 fast Fourier transform; the size must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+  let n = re.len();
+
+  // bit-reversal permutation
+  let mut j = 0;
+
+  for i in 1..n {
+    let mut bit = n >> 1;
+
+    while j & bit != 0 {
+      j ^= bit;
+      bit >>= 1;
+    }
+
+    j |= bit;
+
+    if i < j {
+      re.swap(i, j);
+      im.swap(i, j);
+    }
+  }
+
+  // butterflies
+  let mut len = 2;
+
+  while len <= n {
+    let angle = -2. * PI / len as f32;
+
+    for start in (0..n).step_by(len) {
+      for k in 0..len / 2 {
+        let (w_im, w_re) = unsafe { (sinf32(angle * k as f32), cosf32(angle * k as f32)) };
+        let a = start + k;
+        let b = a + len / 2;
+        let t_re = re[b] * w_re - im[b] * w_im;
+        let t_im = re[b] * w_im + im[b] * w_re;
+
+        re[b] = re[a] - t_re;
+        im[b] = im[a] - t_im;
+        re[a] += t_re;
+        im[a] += t_im;
+      }
+    }
+
+    len <<= 1;
+  }
+}
+
+/// A wave playing a `Wavetable`.
+///
+/// The table is shared, so that many oscillators can play it without copying it. The morph position
+/// can be changed between samples – through `Oscillator::wave_mut` or `Oscillator::sample_morphed` –
+/// to sweep through the frames.
+#[derive(Clone, Debug)]
+pub struct WavetableWave {
+  table: Rc<Wavetable>,
+  pub morph: f32
+}
+
+impl WavetableWave {
+  pub fn new(table: Rc<Wavetable>, morph: f32) -> Self {
+    WavetableWave { table, morph }
+  }
+
+  /// Table played by the wave.
+  pub fn table(&self) -> &Rc<Wavetable> {
+    &self.table
+  }
+}
+
+impl Wave for WavetableWave {
+  #[inline(always)]
+  fn sample(&mut self, t: Hertz, dt: Hertz) -> Sample {
+    self.table.sample(t, dt, self.morph)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SIZE: usize = 64;
+
+  // A band-limited single-cycle frame: DC and a few harmonics, all below the table’s Nyquist.
+  fn frame(harmonic: usize) -> Vec<Sample> {
+    (0..SIZE).map(|j| {
+      let t = 2. * PI * j as f32 / SIZE as f32;
+      0.1 + (harmonic as f32 * t).sin() + 0.5 * (3. * harmonic as f32 * t).cos()
+    }).collect()
+  }
+
+  #[test]
+  fn lookup_reads_single_cycle_tables_exactly() {
+    let frame = frame(2);
+    let table = Wavetable::new(&[&frame]).unwrap();
+    let dt = 1. / 1024.;
+
+    for j in 0..SIZE {
+      let t = j as f32 / SIZE as f32;
+      let next = frame[(j + 1) % SIZE];
+
+      assert!((table.sample(t, dt, 0.) - frame[j]).abs() < 1e-5, "sample {}", j);
+      assert!((table.sample(t + 0.5 / SIZE as f32, dt, 0.) - (frame[j] + next) / 2.).abs() < 1e-5, "sample {}", j);
+    }
+  }
+
+  #[test]
+  fn morph_ends_give_first_and_last_frames() {
+    let frames = [frame(1), frame(2), frame(4)];
+    let table = Wavetable::new(&[&frames[0], &frames[1], &frames[2]]).unwrap();
+    let dt = 1. / 1024.;
+
+    for j in 0..SIZE {
+      let t = j as f32 / SIZE as f32;
+
+      for &(morph, expected) in &[(0., &frames[0]), (1., &frames[2]), (-1., &frames[0]), (2., &frames[2])] {
+        let sample = table.sample(t, dt, morph);
+        assert!((sample - expected[j]).abs() < 1e-5, "morph {}, sample {}", morph, j);
+      }
+    }
+  }
+
+  #[test]
+  fn mip_map_levels_keep_no_partial_above_nyquist() {
+    // a sawtooth, with all harmonics
+    let table = Wavetable::from_fn(SIZE, 1, |_, t| 1. - 2. * t).unwrap();
+
+    for &freq in &[20., 440., 1000., 3000., 5000., 10000., 20000.] {
+      let dt = freq / 44100.;
+      let level = &table.frames[0][table.level(dt)];
+
+      for k in 1..SIZE / 2 {
+        let (re, im) = level.iter().enumerate().fold((0., 0.), |(re, im), (j, x)| {
+          let angle = 2. * PI * (k * j) as f32 / SIZE as f32;
+          (re + x * angle.cos(), im - x * angle.sin())
+        });
+        let amplitude = (re * re + im * im).sqrt() / SIZE as f32;
+
+        if k as f32 * dt >= 0.5 {
+          assert!(amplitude < 1e-4, "harmonic {} at {} Hz: {}", k, freq, amplitude);
+        }
+      }
+    }
+  }
+}
@@ -7,70 +7,18 @@ extern crate hound;
 
 mod streaming;
 
-use alto::Source;
-use hush::envelope::{ADSR, ADSRState};
-use hush::instrument::{Instrument, NoteChannel, Synth};
+use hush::envelope::ADSR;
+use hush::instrument::{Enveloped, Instrument, NoteChannel, Synth};
 use hush::note::{self, Note};
-use hush::sample::Sample;
-use hush::time::{SampleTime, Time};
+use hush::time::SampleRate;
+use hush::unison::Unison;
 use luminance_glfw::surface::{Action, GlfwSurface, Key, Surface, WindowDim, WindowEvent, WindowOpt};
 use std::time::Instant;
 
 // Our instrument is a Synth with an ADSR envelope.
-struct SynthADSR {
-  synth: Synth,
-  envelope: ADSR,
-  source_time: Instant
-}
-
-impl SynthADSR {
-  fn new(synth: Synth) -> Self {
-    let envelope = ADSR::new(0.2, 0.1, 0.9, 1.).expect("ADSR envelope");
-    let source_time = Instant::now();
-
-    SynthADSR { synth, envelope, source_time }
-  }
-
-  pub fn sine() -> Self {
-    Self::new(Synth::sine())
-  }
-
-  pub fn triangle() -> Self {
-    Self::new(Synth::triangle())
-  }
-
-  pub fn square() -> Self {
-    Self::new(Synth::square())
-  }
-
-  pub fn sawtooth() -> Self {
-    Self::new(Synth::sawtooth())
-  }
-}
-
-impl Instrument for SynthADSR {
-  fn note_on(&mut self, note: Note, channel: NoteChannel) {
-    self.synth.note_on(note, channel);
-    self.envelope.on(time_from_instant(&self.source_time));
-  }
-
-  fn note_off(&mut self, _: NoteChannel) {
-    self.envelope.off(time_from_instant(&self.source_time));
-  }
-
-  fn is_active(&self, t: Time) -> bool {
-    match self.envelope.state() {
-      ADSRState::On(_) => true,
-      ADSRState::Off(_) => {
-        // when the signal hits 0, it’s officially unactive
-        self.envelope.get(t) > 0.
-      }
-    }
-  }
-
-  fn get_samples(&mut self, start: SampleTime, end: SampleTime) -> &[Sample] {
-    unimplemented!()
-  }
+fn enveloped(synth: Synth, rate: SampleRate) -> Enveloped<Synth> {
+  let envelope = ADSR::new(0.2, 0.1, 0.9, 1., rate).expect("ADSR envelope");
+  Enveloped::new(synth, envelope)
 }
 
 fn time_from_instant(instant: &Instant) -> f32 {
@@ -81,10 +29,32 @@ fn time_from_instant(instant: &Instant) -> f32 {
   secs + millis * 1e-3
 }
 
+// Map a key to the note it plays; each key plays on its own channel so that chords can be held.
+fn key_note(key: Key) -> Option<(Note, NoteChannel, &'static str)> {
+  let (i, note, name) = match key {
+    Key::Q => (0, note::C4, "C4"),
+    Key::W => (1, note::DB4, "DB4"),
+    Key::E => (2, note::D4, "D4"),
+    Key::R => (3, note::EB4, "EB4"),
+    Key::T => (4, note::E4, "E4"),
+    Key::Y => (5, note::F4, "F4"),
+    Key::U => (6, note::GB4, "GB4"),
+    Key::I => (7, note::G4, "G4"),
+    Key::O => (8, note::AB4, "AB4"),
+    Key::P => (9, note::A4, "A4"),
+    Key::LeftBracket => (10, note::BB4, "BB4"),
+    Key::RightBracket => (11, note::B4, "B4"),
+    _ => return None
+  };
+
+  Some((note, NoteChannel::new(i), name))
+}
+
 fn main() {
   let mut surface = GlfwSurface::new(WindowDim::Windowed(940, 560), "hush piano", WindowOpt::default()).expect("GLFW surface");
 
-  let mut synth = Synth::sine();
+  let rate = SampleRate::default();
+  let mut synth = enveloped(Synth::sine(rate), rate);
 
   // backend stuff: OpenAL here
   let alto = alto::Alto::load_default().unwrap();
@@ -92,7 +62,7 @@ fn main() {
   let mut al_ctx = al_device.new_context(None).unwrap();
 
   // for streaming
-  let mut streamer = streaming::Streamer::new(&mut al_ctx);
+  let mut streamer = streaming::Streamer::new(&mut al_ctx, rate);
 
   // for timing
   let now = Instant::now();
@@ -110,94 +80,55 @@ fn main() {
         WindowEvent::Key(key, _, Action::Press, _) => {
           match key {
             Key::F1 => {
-              synth = Synth::sine();
+              synth = enveloped(Synth::sine(rate), rate);
             }
 
             Key::F2 => {
-              synth = Synth::square();
+              synth = enveloped(Synth::square(rate), rate);
             }
 
             Key::F3 => {
-              synth = Synth::triangle();
+              synth = enveloped(Synth::triangle(rate), rate);
             }
 
             Key::F4 => {
-              synth = Synth::sawtooth();
-            }
-
-            Key::Q => {
-              println!("on C4");
-              synth.note_on(note::C4, NoteChannel::default());
-            }
-
-            Key::W => {
-              println!("on DB4");
-              synth.note_on(note::DB4, NoteChannel::default());
-            }
-
-            Key::E => {
-              println!("on D4");
-              synth.note_on(note::D4, NoteChannel::default());
+              synth = enveloped(Synth::sawtooth(rate), rate);
             }
 
-            Key::R => {
-              println!("on EB4");
-              synth.note_on(note::EB4, NoteChannel::default());
+            Key::F5 => {
+              synth = enveloped(Synth::square_bandlimited(rate), rate);
             }
 
-            Key::T => {
-              println!("on E4");
-              synth.note_on(note::E4, NoteChannel::default());
+            Key::F6 => {
+              synth = enveloped(Synth::triangle_bandlimited(rate), rate);
             }
 
-            Key::Y => {
-              println!("on F4");
-              synth.note_on(note::F4, NoteChannel::default());
+            Key::F7 => {
+              synth = enveloped(Synth::sawtooth_bandlimited(rate), rate);
             }
 
-            Key::U => {
-              println!("on GB4");
-              synth.note_on(note::GB4, NoteChannel::default());
+            Key::F8 => {
+              synth = enveloped(Synth::pulse(0.25, rate), rate);
             }
 
-            Key::I => {
-              println!("on G4");
-              synth.note_on(note::G4, NoteChannel::default());
+            Key::F9 => {
+              synth = enveloped(Synth::sawtooth_bandlimited(rate).with_unison(Unison::supersaw()), rate);
             }
 
-            Key::O => {
-              println!("on AB4");
-              synth.note_on(note::AB4, NoteChannel::default());
+            key => {
+              if let Some((note, channel, name)) = key_note(key) {
+                println!("on {}", name);
+                synth.note_on(note, channel);
+              }
             }
-
-            Key::P => {
-              println!("on A4");
-              synth.note_on(note::A4, NoteChannel::default());
-            }
-
-            Key::LeftBracket => {
-              println!("on BB4");
-              synth.note_on(note::BB4, NoteChannel::default());
-            }
-
-            Key::RightBracket => {
-              println!("on B4");
-              synth.note_on(note::B4, NoteChannel::default());
-            }
-
-            _ => ()
           }
         }
 
         // key off
         WindowEvent::Key(key, _, Action::Release, _) => {
-          match key {
-            Key::Q | Key::W | Key::E | Key::R | Key::T | Key::Y | Key::U | Key::I | Key::O | Key::P | Key::LeftBracket | Key::RightBracket => {
-              println!("off");
-              synth.note_off(NoteChannel::default());
-            }
-
-            _ => ()
+          if let Some((_, channel, name)) = key_note(key) {
+            println!("off {}", name);
+            synth.note_off(channel);
           }
         }
 
@@ -1,9 +1,6 @@
-use alto::{Buffer, Context, Mono, Source, SourceState, StreamingSource};
+use alto::{Buffer, Context, Source, SourceState, Stereo, StreamingSource};
 use hush::instrument::Instrument;
-use hush::time::{SampleTime, Time};
-
-const SAMPLE_FREQ: usize = 44100;
-const SAMPLE_READAHEAD: usize = 44100; // one second ahead
+use hush::time::{SampleRate, SampleTime, Time};
 
 // Handle audio streaming.
 //
@@ -13,18 +10,28 @@ pub struct Streamer {
   source: StreamingSource,
   buffers: Vec<Buffer>,
   processed_samples_nb: usize, // number of samples already processed
+  rate: SampleRate,
+  readahead: usize, // one second ahead
+  left: Vec<f32>,
+  right: Vec<f32>,
+  frames: Vec<Stereo<f32>>, // interleaved left and right samples
 }
 
 impl Streamer {
-  pub fn new(al_ctx: &mut Context) -> Self {
+  pub fn new(al_ctx: &mut Context, rate: SampleRate) -> Self {
     let processed_samples_nb = 0;
     let source = al_ctx.new_streaming_source().expect("OpenAL source");
+    let readahead = rate.0 as usize;
 
     let buffers = (0..2).into_iter().map(|_| {
-      al_ctx.new_buffer::<Mono<f32>, _>(&vec![0.; SAMPLE_FREQ], SAMPLE_FREQ as i32).unwrap()
+      al_ctx.new_buffer::<Stereo<f32>, _>(&vec![Stereo { left: 0., right: 0. }; readahead], rate.0 as i32).unwrap()
     }).collect::<Vec<_>>();
 
-    Self { source, buffers, processed_samples_nb }
+    let left = vec![0.; readahead];
+    let right = vec![0.; readahead];
+    let frames = Vec::with_capacity(readahead);
+
+    Self { source, buffers, processed_samples_nb, rate, readahead, left, right, frames }
   }
 
   /// Refresh the streaming process to check whether the DSP and/or streaming buffers should be
@@ -66,17 +73,20 @@ impl Streamer {
     // TODO: maybe add the possibility to add more buffer?
     let mut buffer = self.buffers.swap_remove(0);
     let start = self.processed_samples_nb;
-    let end = start + SAMPLE_READAHEAD;
-    let samples = instrument.get_samples(SampleTime(start), SampleTime(end));
+    let end = start + self.readahead;
+    instrument.get_stereo_samples(SampleTime(start), SampleTime(end), &mut self.left, &mut self.right);
+
+    self.frames.clear();
+    self.frames.extend(self.left.iter().zip(&self.right).map(|(&left, &right)| Stereo { left, right }));
 
     // upload the samples to the DSP buffer
-    let _ = buffer.set_data::<Mono<f32>, _>(samples, SAMPLE_FREQ as i32);
+    let _ = buffer.set_data::<Stereo<f32>, _>(&self.frames, self.rate.0 as i32);
 
     // queue the buffer to the current DSP source
     let _ = self.source.queue_buffer(buffer);
 
     // update the number of samples already processed
-    self.processed_samples_nb += SAMPLE_READAHEAD;
+    self.processed_samples_nb += self.readahead;
   }
 
   // Check what to do while an instrument is active and the DSP playing.